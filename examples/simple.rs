@@ -17,6 +17,8 @@ async fn main() {
         ),
         server_url: "http://localhost:8086".to_string(),
         body_limit: 16 * 1024,
+        max_limit: 1000,
+        ..Config::default()
     })
     .await;
     r.run().await;
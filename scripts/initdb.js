@@ -3,4 +3,10 @@ use rhymer-test;
 
 db.auth("rhymer-test", "rhymer-test");
 
-db.getCollection("_User").createIndex({"username": 1}, { unique: true, });
\ No newline at end of file
+db.getCollection("_User").createIndex({"username": 1}, { unique: true, });
+
+db.getCollection("_SCHEMA").createIndex({"className": 1}, { unique: true, });
+
+db.getCollection("_Role").createIndex({"name": 1}, { unique: true, });
+
+db.getCollection("_Session").createIndex({"tokenId": 1}, { unique: true, });
@@ -1,35 +1,151 @@
 use std::collections::HashMap;
 
-use mongodb::bson::{doc, Document};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use warp::Rejection;
+
+use crate::{database, error::bad_request, role, user::UserKind};
+
+/// Access granted to a user, role or the public, each of which is independent of the others,
+/// e.g. write-only for drop boxes or updating without deletion.
+///
+/// Encoded as `{"read": bool, "write": bool, "delete": bool}` in ACL, while the legacy
+/// encoding of `i`, `r` and `w` is still understood as invisible, read-only and read-write
+/// (with deletion) respectively.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Access {
+    /// Whether the object can be retrieved.
+    #[serde(default)]
+    pub read: bool,
+    /// Whether the object can be updated.
+    #[serde(default)]
+    pub write: bool,
+    /// Whether the object can be deleted.
+    #[serde(default)]
+    pub delete: bool,
+}
 
-use crate::database;
+impl Access {
+    /// Nothing granted, i.e. invisible.
+    pub const NONE: Access = Access {
+        read: false,
+        write: false,
+        delete: false,
+    };
+    /// Read-only.
+    pub const READ_ONLY: Access = Access {
+        read: true,
+        write: false,
+        delete: false,
+    };
+    /// Read, write and delete.
+    pub const ALL: Access = Access {
+        read: true,
+        write: true,
+        delete: true,
+    };
 
-#[derive(PartialEq, Debug, Copy, Clone)]
-pub enum AclKind {
-    Invisible,
-    ReadOnly,
-    ReadWrite,
+    /// Decode from an entry of ACL in either encoding, or `None` if invalid.
+    pub fn from_bson(v: &Bson) -> Option<Self> {
+        match v {
+            Bson::String(s) => match s.as_str() {
+                "i" => Some(Self::NONE),
+                "r" => Some(Self::READ_ONLY),
+                "w" => Some(Self::ALL),
+                _ => None,
+            },
+            Bson::Document(d) => {
+                let flag = |k: &str| d.get_bool(k).unwrap_or(false);
+                Some(Access {
+                    read: flag("read"),
+                    write: flag("write"),
+                    delete: flag("delete"),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Check if everything granted by `other` is granted by this as well.
+    pub fn includes(&self, other: Access) -> bool {
+        (self.read || !other.read) && (self.write || !other.write) && (self.delete || !other.delete)
+    }
+
+    /// Union of both, granting whatever granted by either of them.
+    pub fn union(self, other: Access) -> Access {
+        Access {
+            read: self.read || other.read,
+            write: self.write || other.write,
+            delete: self.delete || other.delete,
+        }
+    }
+
+    /// Check if `kind` is granted.
+    pub(crate) fn allows(&self, kind: AccessKind) -> bool {
+        match kind {
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+            AccessKind::Delete => self.delete,
+        }
+    }
 }
 
-impl Default for AclKind {
-    fn default() -> Self {
-        AclKind::ReadWrite
+impl From<Access> for Bson {
+    fn from(a: Access) -> Self {
+        Bson::Document(doc! {"read": a.read, "write": a.write, "delete": a.delete})
     }
 }
 
-#[derive(Debug, Default)]
-struct AclItem {
-    pub uid: String,
-    pub acl: AclKind,
+/// Kinds of access checked against ACL by databases.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub(crate) enum AccessKind {
+    Read,
+    Write,
+    Delete,
+}
+
+impl AccessKind {
+    /// Field of this kind in encoded `Access`.
+    pub(crate) fn field(self) -> &'static str {
+        match self {
+            AccessKind::Read => "read",
+            AccessKind::Write => "write",
+            AccessKind::Delete => "delete",
+        }
+    }
+
+    /// Flags of the legacy encoding granting this kind.
+    pub(crate) fn legacy_flags(self) -> &'static [&'static str] {
+        match self {
+            AccessKind::Read => &["r", "w"],
+            AccessKind::Write | AccessKind::Delete => &["w"],
+        }
+    }
+
+    /// Check if entry `v` of ACL grants this kind.
+    pub(crate) fn granted_by(self, v: &Bson) -> bool {
+        Access::from_bson(v).map_or(false, |a| a.allows(self))
+    }
 }
 
 /// Access control of each object.
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Acl {
-    /// Per-user access control, key is user_id
-    pub user: HashMap<String, AclKind>,
+    /// Per-user access control, key is user_id, or `role:NAME` for users of the role
+    pub user: HashMap<String, Access>,
     /// Access control on other users
-    pub other: AclKind,
+    pub other: Access,
+}
+
+/// Read-write by all users.
+impl Default for Acl {
+    fn default() -> Self {
+        Acl {
+            user: HashMap::new(),
+            other: Access::ALL,
+        }
+    }
 }
 
 impl Acl {
@@ -38,113 +154,246 @@ impl Acl {
         Self::default()
     }
 
-    // pub fn from_readers(user_ids: Vec<String>) -> Self {
-    //     let mut x = Self::new();
-    //     for i in user_ids {
-    //         x.add_reader(i);
-    //     }
-    //     x
-    // }
-    // pub fn from_writers(user_ids: Vec<String>) -> Self {
-    //     let mut x = Self::new();
-    //     for i in user_ids {
-    //         x.add_writer(i);
-    //     }
-    //     x
-    // }
-    // pub fn add_reader(&mut self, uid: String) {
-    //     self.user.insert(uid, AclKind::ReadOnly);
-    // }
-    // pub fn add_writer(&mut self, uid: String) {
-    //     self.user.insert(uid, AclKind::ReadWrite);
-    // }
+    /// Access of user with user_id, falling back to that of public if not specified.
+    pub fn access_of_user(&self, user_id: &str) -> Access {
+        self.user.get(user_id).copied().unwrap_or(self.other)
+    }
+
+    /// Access of `user` with its roles, resolved the same as databases do, i.e. its own
+    /// entry, else the union of those of its roles if any, else that of public.
+    pub fn access_of(&self, user: &UserKind) -> Access {
+        match user {
+            UserKind::Master => Access::ALL,
+            UserKind::Client(t) => match self.user.get(&t.id) {
+                Some(a) => *a,
+                None => t
+                    .roles
+                    .iter()
+                    .filter_map(|r| self.user.get(&role::key(r)))
+                    .fold(None, |u: Option<Access>, a| {
+                        Some(u.map_or(*a, |u| u.union(*a)))
+                    })
+                    .unwrap_or(self.other),
+            },
+            UserKind::Guest => self.other,
+        }
+    }
 
     /// Check if readable by user with user_id
     pub fn readable_by_user(&self, user_id: &str) -> bool {
-        if let Some(k) = self.user.get(user_id) {
-            match k {
-                AclKind::Invisible => false,
-                _ => true,
-            }
-        } else {
-            self.readable_by_public()
-        }
+        self.access_of_user(user_id).read
     }
     /// Check if writable by user with user_id
     pub fn writable_by_user(&self, user_id: &str) -> bool {
-        if let Some(k) = self.user.get(user_id) {
-            match k {
-                AclKind::ReadWrite => true,
-                _ => false,
-            }
-        } else {
-            self.writable_by_public()
-        }
+        self.access_of_user(user_id).write
+    }
+    /// Check if deletable by user with user_id
+    pub fn deletable_by_user(&self, user_id: &str) -> bool {
+        self.access_of_user(user_id).delete
     }
     /// Check if readable by public (users not specified in ACL)
     pub fn readable_by_public(&self) -> bool {
-        match self.other {
-            AclKind::Invisible => false,
-            _ => true,
-        }
+        self.other.read
     }
 
     /// Check if writable by public (users not specified in ACL)
     pub fn writable_by_public(&self) -> bool {
-        match self.other {
-            AclKind::ReadWrite => true,
-            _ => false,
-        }
+        self.other.write
+    }
+
+    /// Check if deletable by public (users not specified in ACL)
+    pub fn deletable_by_public(&self) -> bool {
+        self.other.delete
+    }
+
+    /// Set access of user, e.g. write-only by `Access { write: true, ..Access::NONE }`.
+    pub fn set_access(&mut self, user_id: impl Into<String>, access: Access) {
+        self.user.insert(user_id.into(), access);
     }
     /// Set read-only by user.
     pub fn set_readonly(&mut self, user_id: impl Into<String>) {
-        self.user.insert(user_id.into(), AclKind::ReadOnly);
+        self.set_access(user_id, Access::READ_ONLY);
     }
     /// Set invisiable by user.
     pub fn set_invisiable(&mut self, user_id: impl Into<String>) {
-        self.user.insert(user_id.into(), AclKind::Invisible);
+        self.set_access(user_id, Access::NONE);
     }
     /// Set writable by user.
     pub fn set_writable(&mut self, user_id: impl Into<String>) {
-        self.user.insert(user_id.into(), AclKind::ReadWrite);
+        self.set_access(user_id, Access::ALL);
     }
 
-    /// Set writable by other users.
+    /// Set access of users of role `name` and its child roles.
+    pub fn set_role_access(&mut self, name: impl AsRef<str>, access: Access) {
+        self.user.insert(role::key(name.as_ref()), access);
+    }
+    /// Set read-only by users of role `name` and its child roles.
+    pub fn set_role_readonly(&mut self, name: impl AsRef<str>) {
+        self.set_role_access(name, Access::READ_ONLY);
+    }
+    /// Set invisiable by users of role `name` and its child roles.
+    pub fn set_role_invisiable(&mut self, name: impl AsRef<str>) {
+        self.set_role_access(name, Access::NONE);
+    }
+    /// Set writable by users of role `name` and its child roles.
+    pub fn set_role_writable(&mut self, name: impl AsRef<str>) {
+        self.set_role_access(name, Access::ALL);
+    }
+
+    /// Set access of other users.
+    pub fn set_public_access(&mut self, access: Access) {
+        self.other = access;
+    }
+
+    /// Set read-only by other users.
     pub fn set_public_readonly(&mut self) {
-        self.other = AclKind::ReadOnly;
+        self.other = Access::READ_ONLY;
     }
 
     /// Set invisiable by other users.
     pub fn set_public_invisiable(&mut self) {
-        self.other = AclKind::Invisible;
+        self.other = Access::NONE;
     }
 
     /// Set writable by other users.
     pub fn set_public_writable(&mut self) {
-        self.other = AclKind::ReadWrite;
+        self.other = Access::ALL;
+    }
+}
+
+/// Default ACL of objects of a class created through RESTFul API, where the creator is filled
+/// in on creation, e.g. `AclTemplate::new(acl).creator(Access::ALL)` with `acl` public read-only
+/// for creator read-write and public read-only.
+#[derive(Debug, Default, Clone)]
+pub struct AclTemplate {
+    acl: Acl,
+    creator: Option<Access>,
+}
+
+impl AclTemplate {
+    /// Create a template of ACL granting nothing to the creator specifically.
+    pub fn new(acl: Acl) -> Self {
+        AclTemplate { acl, creator: None }
+    }
+
+    /// Template of objects only visible to their creators.
+    pub fn owner_only() -> Self {
+        let mut acl = Acl::new();
+        acl.set_public_invisiable();
+        Self::new(acl).creator(Access::ALL)
+    }
+
+    /// Grant `access` to the creator, which takes precedence over other entries.
+    pub fn creator(mut self, access: Access) -> Self {
+        self.creator = Some(access);
+        self
+    }
+
+    /// ACL of an object created by `user`, where the creator entry applies to clients only.
+    pub fn apply(&self, user: &UserKind) -> Acl {
+        let mut acl = self.acl.clone();
+        if let (UserKind::Client(t), Some(access)) = (user, self.creator) {
+            acl.set_access(t.id.clone(), access);
+        }
+        acl
+    }
+}
+
+/// ACL of object `doc`, or the default one if not set.
+pub(crate) fn of(doc: &Document) -> Acl {
+    doc.get_document(database::ACL)
+        .map_or_else(|_| Acl::default(), |d| Acl::from(d.clone()))
+}
+
+/// Check if `key` is a valid key of ACL, i.e. `*`, a user id or `role:NAME`.
+fn acl_key(key: &str) -> bool {
+    if key == "*" {
+        return true;
+    }
+    match key.strip_prefix(role::PREFIX) {
+        Some(name) => role::role_name(name),
+        None => {
+            !key.is_empty()
+                && !key.starts_with('$')
+                && !key.contains(|c: char| c == '.' || c == '"')
+        }
     }
 }
 
-impl Into<Document> for Acl {
-    fn into(self) -> Document {
-        let mut acl = self.user;
-        acl.insert("*".to_string(), self.other);
+/// Field of ACL in requests of and responses to clients, e.g. `{"ACL": {"*": {"read": true}}}`.
+pub(crate) const CLIENT_ACL: &'static str = "ACL";
 
+/// Parse ACL sent by clients, in either encoding of `Access`, rejecting invalid keys and
+/// entries instead of ignoring them, where public is granted nothing if `*` is missing.
+pub(crate) fn parse(v: Bson) -> Result<Acl, Rejection> {
+    let d = match v {
+        Bson::Document(d) => d,
+        v => return bad_request(format!("Invalid ACL {}", v)),
+    };
+    for (k, v) in d.iter() {
+        if !acl_key(k) {
+            return bad_request(format!("Invalid ACL key {}", k));
+        }
+        let valid = match v {
+            Bson::String(_) => Access::from_bson(v).is_some(),
+            Bson::Document(a) => a.iter().all(|(f, v)| {
+                ["read", "write", "delete"].contains(&f.as_str()) && matches!(v, Bson::Boolean(_))
+            }),
+            _ => false,
+        };
+        if !valid {
+            return bad_request(format!("Invalid ACL entry of {}: {}", k, v));
+        }
+    }
+    let public = d.contains_key("*");
+    let mut acl = Acl::from(d);
+    if !public {
+        acl.other = Access::NONE;
+    }
+    Ok(acl)
+}
+
+impl From<Acl> for Document {
+    fn from(acl: Acl) -> Self {
         let mut d = Document::new();
-        for (uid, acl) in acl.iter() {
-            d.insert(
-                uid,
-                match acl {
-                    AclKind::Invisible => "i",
-                    AclKind::ReadOnly => "r",
-                    AclKind::ReadWrite => "w",
-                },
-            );
+        d.insert("*", acl.other);
+        for (uid, access) in acl.user {
+            d.insert(uid, access);
         }
         d
     }
 }
 
+/// Decode ACL stored in objects, where invalid entries are ignored and public access
+/// defaults to read-write if missing.
+impl From<Document> for Acl {
+    fn from(d: Document) -> Self {
+        let mut acl = Acl::new();
+        for (k, v) in d.iter() {
+            match Access::from_bson(v) {
+                Some(a) if k == "*" => acl.other = a,
+                Some(a) => {
+                    acl.user.insert(k.to_string(), a);
+                }
+                None => warn!("invalid ACL entry of {}: {}", k, v),
+            }
+        }
+        acl
+    }
+}
+
+impl Serialize for Acl {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Document::from(self.clone()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Acl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Document::deserialize(deserializer).map(Acl::from)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
@@ -164,7 +413,7 @@ mod tests {
     #[test]
     fn test_acl_new() {
         let acl = Acl::new();
-        assert!(acl.other == AclKind::ReadWrite);
+        assert!(acl.other == Access::ALL);
         assert!(acl.user.len() == 0);
         assert!(acl.readable_by_public() && acl.writable_by_public());
         assert!(acl.readable_by_user("foo") && acl.writable_by_user("bar"));
@@ -182,6 +431,50 @@ mod tests {
         assert!(acl.readable_by_user(uid) && acl.writable_by_user(uid));
     }
 
+    #[test]
+    fn test_acl_encoding() {
+        let mut acl = Acl::new();
+        let write_only = Access {
+            write: true,
+            ..Access::NONE
+        };
+        acl.set_access("drop", write_only);
+        acl.set_role_access(
+            "Editor",
+            Access {
+                delete: false,
+                ..Access::ALL
+            },
+        );
+        acl.set_public_readonly();
+        assert!(!acl.readable_by_user("drop") && acl.writable_by_user("drop"));
+        assert!(acl.readable_by_user("foo") && !acl.deletable_by_user("foo"));
+
+        let d = Document::from(acl.clone());
+        assert_eq!(
+            d.get_document("drop").unwrap(),
+            &doc! {"read": false, "write": true, "delete": false}
+        );
+        assert_eq!(Acl::from(d), acl);
+        let v = serde_json::to_value(&acl).unwrap();
+        assert_eq!(
+            v["role:Editor"],
+            json!({"read": true, "write": true, "delete": false})
+        );
+        assert_eq!(serde_json::from_value::<Acl>(v).unwrap(), acl);
+
+        // Legacy encoding.
+        let acl = Acl::from(doc! {"a": "w", "b": "r", "c": "i", "*": "r", "d": 1});
+        assert_eq!(acl.user.len(), 3);
+        assert_eq!(acl.access_of_user("a"), Access::ALL);
+        assert_eq!(acl.access_of_user("b"), Access::READ_ONLY);
+        assert_eq!(acl.access_of_user("c"), Access::NONE);
+        assert_eq!(acl.access_of_user("d"), Access::READ_ONLY);
+        assert_eq!(Acl::from(doc! {}).other, Access::ALL);
+        assert_eq!(Acl::from(doc! {"*": {}}).other, Access::NONE);
+        assert_eq!(serde_json::from_value::<Access>(json!({})).unwrap(), Access::NONE);
+    }
+
     const ACL_TEST_USERS: &[&'static str] = &["a", "b"];
 
     async fn save_acl(
@@ -0,0 +1,332 @@
+use std::{collections::HashMap, sync::Arc};
+
+use mongodb::bson::{Bson, Document};
+use serde_json::{json, Value};
+use warp::{hyper::StatusCode, Rejection, Reply};
+
+use crate::{
+    error::{self, bad_request, not_found},
+    object,
+    server::{Context, Request},
+    types, user,
+    validator::ClassName,
+};
+
+/// Run a list of requests of form `{"method": ..., "path": ..., "body": ...}` in order,
+/// used by RESTFul API.
+///
+/// Each request is handled as if sent alone by the same user, and the response is a list of
+/// `{"success": ...}` or `{"error": {"code": ..., "error": ...}}` for each of them.
+/// With `"transaction": true`, all of them take effect only if all succeed, otherwise
+/// the response is the error of the first failed one.
+pub async fn run(req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejection> {
+    let mut body = match req.body.clone() {
+        Some(d) => d,
+        None => return bad_request("Cannot run batch with empty body"),
+    };
+    let transaction = body.get_bool("transaction").unwrap_or(false);
+    let requests = match body.remove("requests") {
+        Some(Bson::Array(v)) => v,
+        _ => return bad_request("Expect an array of requests"),
+    };
+    let mut subs = vec![];
+    for r in requests {
+        match r {
+            Bson::Document(d) => subs.push(parse(d)?),
+            _ => return bad_request("Expect a request of object"),
+        }
+    }
+    trace!(
+        "batch of {} requests, transaction: {}",
+        subs.len(),
+        transaction
+    );
+
+    let ctx = if transaction {
+        let mut c = (*ctx).clone();
+        c.db = ctx.db.transaction().await?;
+        Arc::new(c)
+    } else {
+        ctx
+    };
+
+    let mut results = vec![];
+    for (method, path, body) in subs {
+        let mut r = req.clone();
+        r.body = body;
+        let (status, v) = dispatch(&method, &path, r, ctx.clone()).await;
+        if status.is_success() {
+            results.push(json!({ "success": v }));
+        } else if transaction {
+            ctx.db.abort().await?;
+            return Ok(warp::reply::with_status(warp::reply::json(&v), status));
+        } else {
+            results.push(json!({ "error": v }));
+        }
+    }
+    if transaction {
+        ctx.db.commit().await?;
+    }
+    Ok(warp::reply::with_status(
+        warp::reply::json(&results),
+        StatusCode::OK,
+    ))
+}
+
+fn parse(mut d: Document) -> Result<(String, String, Option<Document>), Rejection> {
+    let method = match d.remove("method") {
+        Some(Bson::String(m)) => m.to_uppercase(),
+        _ => return bad_request("Expect method of request"),
+    };
+    let path = match d.remove("path") {
+        Some(Bson::String(p)) => p,
+        _ => return bad_request("Expect path of request"),
+    };
+    let body = match d.remove("body") {
+        Some(Bson::Document(b)) => Some(b),
+        None | Some(Bson::Null) => None,
+        _ => return bad_request("Expect body of request to be an object"),
+    };
+    Ok((method, path, body))
+}
+
+/// Handle a single request by the same handler of the route, returning the status and body of
+/// the response.
+///
+/// Query parameters of `GET` requests are taken from the body, where non-string values such as
+/// `where` are encoded in JSON. Query strings in `path` are not supported and rejected.
+async fn dispatch(
+    method: &str,
+    path: &str,
+    req: Request,
+    ctx: Arc<Context>,
+) -> (StatusCode, Value) {
+    if path.contains('?') {
+        let e = bad_request("Query string in path is not supported, use body instead");
+        return response::<String>(e).await;
+    }
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let class = |c: &str| {
+        c.parse::<ClassName>()
+            .map_or_else(|_| not_found(format!("Invalid class name {}", c)), Ok)
+    };
+    macro_rules! reply {
+        ($r:expr) => {
+            match $r {
+                Ok(r) => response(r.await).await,
+                Err(e) => response::<String>(Err(e)).await,
+            }
+        };
+    }
+    trace!("batch {} {}", method, path);
+    match (method, segments.as_slice()) {
+        ("POST", ["users"]) => response(user::signup(req, ctx).await).await,
+        ("POST", ["users", id]) => {
+            response(user::update(id.to_string(), HashMap::new(), req, ctx).await).await
+        }
+        ("POST", ["classes", c]) => reply!(class(*c).map(|c| object::create(c, req, ctx))),
+        ("GET", ["classes", c]) => {
+            let q = req.body.clone().map_or_else(HashMap::new, query_of);
+            reply!(class(*c).map(|c| object::retrieve_by_filter(c, q, req, ctx)))
+        }
+        ("GET", ["classes", c, id]) => {
+            let q = req.body.clone().map_or_else(HashMap::new, query_of);
+            reply!(class(*c).map(|c| object::retrieve(c, id.to_string(), q, req, ctx)))
+        }
+        ("PUT", ["classes", c, id]) => {
+            reply!(class(*c).map(|c| object::update(c, id.to_string(), HashMap::new(), req, ctx)))
+        }
+        ("DELETE", ["classes", c, id]) => {
+            reply!(class(*c).map(|c| object::delete(c, id.to_string(), req, ctx)))
+        }
+        _ => response::<String>(not_found(format!("Cannot batch {} {}", method, path))).await,
+    }
+}
+
+fn query_of(body: Document) -> HashMap<String, String> {
+    body.into_iter()
+        .map(|(k, v)| match v {
+            Bson::String(s) => (k, s),
+            v => (
+                k,
+                serde_json::to_string(&types::encode(v)).unwrap_or_default(),
+            ),
+        })
+        .collect()
+}
+
+async fn response<T: Reply>(r: Result<T, Rejection>) -> (StatusCode, Value) {
+    let resp = match r {
+        Ok(r) => r.into_response(),
+        Err(e) => match error::handle_rejection(e).await {
+            Ok(r) => r.into_response(),
+            Err(e) => {
+                error!("batch request rejected: {:?}", e);
+                let code = StatusCode::INTERNAL_SERVER_ERROR;
+                let v = json!({"code": code.as_u16(), "error": "Unexpected error"});
+                return (code, v);
+            }
+        },
+    };
+    let status = resp.status();
+    let v = warp::hyper::body::to_bytes(resp.into_body())
+        .await
+        .map_or(Value::Null, |b| {
+            serde_json::from_slice(&b[..]).unwrap_or(Value::Null)
+        });
+    (status, v)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+    use warp::hyper::StatusCode;
+
+    use super::super::tests::{escape, test_api, TEST_SERVER_KEY};
+    use crate::{login1, signup1, with_user};
+
+    #[tokio::test]
+    async fn test_batch() {
+        let api = test_api().await;
+        let batch1 = async move |api, body: Value| {
+            with_user!("foo", "POST")
+                .path("/batch")
+                .json(&body)
+                .reply(api)
+                .await
+        };
+
+        let resp = batch1(
+            &api,
+            json!({"requests": [
+                {"method": "POST", "path": "/classes/foo", "body": {"name": "a"}},
+                {"method": "POST", "path": "/classes/foo", "body": {"name": "b"}},
+                {"method": "PUT", "path": "/classes/foo/000000000000000000000000", "body": {"name": "c"}},
+                {"method": "GET", "path": "/nowhere"},
+                {"method": "GET", "path": "/login", "body": {"username": "a", "password": "b"}},
+                {"method": "GET", "path": "/classes/foo?limit=1"},
+            ]}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        let results = body.as_array().unwrap();
+        assert_eq!(results.len(), 6);
+        assert_eq!(results[0]["success"]["name"], json!("a"));
+        assert_eq!(results[1]["success"]["name"], json!("b"));
+        assert_eq!(results[2]["error"]["code"], json!(404));
+        assert_eq!(results[3]["error"]["code"], json!(404));
+        assert_eq!(results[4]["error"]["code"], json!(404));
+        assert_eq!(results[5]["error"]["code"], json!(400));
+        let id = results[0]["success"]["objectId"].as_str().unwrap();
+
+        // Query, update and delete in a batch.
+        let resp = batch1(
+            &api,
+            json!({"requests": [
+                {"method": "GET", "path": "/classes/foo", "body": {"where": {"name": "b"}}},
+                {"method": "PUT", "path": format!("/classes/foo/{}", id), "body": {"name": "c"}},
+                {"method": "GET", "path": format!("/classes/foo/{}", id)},
+                {"method": "DELETE", "path": format!("/classes/foo/{}", id)},
+                {"method": "GET", "path": format!("/classes/foo/{}", id)},
+            ]}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        let results = body.as_array().unwrap();
+        let found = results[0]["success"].as_array().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0]["name"], json!("b"));
+        assert_eq!(results[1]["success"]["name"], json!("c"));
+        assert_eq!(results[2]["success"]["name"], json!("c"));
+        assert_eq!(results[3]["success"]["objectId"], json!(id));
+        assert_eq!(results[4]["error"]["code"], json!(404));
+
+        // Invalid requests.
+        let resp = batch1(&api, json!({"requests": [{"path": "/classes/foo"}]})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = batch1(&api, json!({"requests": {}})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Transactions are not supported in memory.
+        if std::env::var("RHYMER_TEST_DATABASE_URL").is_err() {
+            let resp = batch1(
+                &api,
+                json!({"transaction": true, "requests": [
+                    {"method": "POST", "path": "/classes/foo", "body": {"name": "a"}},
+                ]}),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batch_abort() {
+        // Transactions are only supported by MongoDB.
+        match std::env::var("RHYMER_TEST_DATABASE_URL") {
+            Ok(url) if url.starts_with("mongodb://") => {}
+            _ => return,
+        }
+        let api = test_api().await;
+        let agent = "batch-abort";
+        let batch1 = async move |api, body: Value| {
+            with_user!("foo", "POST")
+                .header("user-agent", agent)
+                .path("/batch")
+                .json(&body)
+                .reply(api)
+                .await
+        };
+        let find1 = async move |api, path, filter: Value| {
+            let resp = warp::test::request()
+                .header("x-parse-master-key", TEST_SERVER_KEY)
+                .path(&format!("{}?where={}", path, escape(filter)))
+                .reply(api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            body["results"].as_array().unwrap().len()
+        };
+
+        // Nothing is written if any request fails.
+        let resp = batch1(
+            &api,
+            json!({"transaction": true, "requests": [
+                {"method": "POST", "path": "/classes/foo", "body": {"name": "aborted"}},
+                {"method": "PUT", "path": "/classes/foo/000000000000000000000000", "body": {"name": "c"}},
+            ]}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(find1(&api, "/classes/foo", json!({"name": "aborted"})).await, 0);
+
+        // Nor users signed up with their sessions.
+        let resp = batch1(
+            &api,
+            json!({"transaction": true, "requests": [
+                {"method": "POST", "path": "/users", "body": {"username": "aborted", "password": "p"}},
+                {"method": "GET", "path": "/nowhere"},
+            ]}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(login1!(&api, "aborted", "p").status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(find1(&api, "/sessions", json!({"userAgent": agent})).await, 0);
+        assert_eq!(signup1!(&api, "aborted", "p").status(), StatusCode::CREATED);
+
+        // Otherwise all of them take effect.
+        let resp = batch1(
+            &api,
+            json!({"transaction": true, "requests": [
+                {"method": "POST", "path": "/classes/foo", "body": {"name": "committed"}},
+                {"method": "POST", "path": "/users", "body": {"username": "committed", "password": "p"}},
+            ]}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(find1(&api, "/classes/foo", json!({"name": "committed"})).await, 1);
+        assert_eq!(login1!(&api, "committed", "p").status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,434 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use warp::Rejection;
+
+use super::{
+    apply, expose, get_path, near, resolve, select, subqueries, update_check, update_doc, Database,
+    Operation, ACL, CREATED_AT, ID, OBJECT_ID,
+};
+use crate::{
+    acl::AccessKind,
+    error::{self, bad_request, conflict, not_found},
+    query::{self, Constraint, Filter, Order, Query},
+    role,
+    types::GeoPoint,
+    user::{ClientToken, UserKind},
+};
+
+/// URL scheme that selects the in-memory backend, e.g. `memory://`.
+pub const SCHEME: &'static str = "memory://";
+
+/// Fields that should be unique in certain classes, same as indexes created by `scripts/initdb.js`.
+const UNIQUE: &[(&'static str, &'static str)] = &[
+    ("_User", "username"),
+    ("_SCHEMA", "className"),
+    ("_Role", "name"),
+    ("_Session", "tokenId"),
+];
+
+/// Database keeping all objects in memory of current process.
+///
+/// Documents are stored the same way as in MongoDB, i.e. with `_id` of `ObjectId`
+/// and `updatedAt` of datetime, so that they are exposed to client identically.
+/// Cloned instances share the same storage.
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    classes: Arc<Mutex<HashMap<String, Vec<Document>>>>,
+}
+
+/// Compare values of the same type the way MongoDB does, where numbers of different types are comparable.
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    let num = |x: &Bson| match x {
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        Bson::Double(f) => Some(*f),
+        _ => None,
+    };
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        _ => match (num(a), num(b)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b),
+            _ if a == b => Some(Ordering::Equal),
+            _ => None,
+        },
+    }
+}
+
+/// Check if any of the values of field `v`, i.e. itself and its elements if it is an array, satisfies `f`.
+fn any(v: Option<&Bson>, f: impl Fn(&Bson) -> bool) -> bool {
+    match v {
+        Some(Bson::Array(a)) => f(v.unwrap()) || a.iter().any(f),
+        Some(v) => f(v),
+        None => false,
+    }
+}
+
+fn equal(v: Option<&Bson>, x: &Bson) -> bool {
+    match x {
+        Bson::Null if v.is_none() => true,
+        _ => any(v, |y| compare(y, x) == Some(Ordering::Equal)),
+    }
+}
+
+fn satisfies(v: Option<&Bson>, c: &Constraint) -> Result<bool, Rejection> {
+    let cmp = |x: &Bson, f: fn(Ordering) -> bool| any(v, |y| compare(y, x).map_or(false, f));
+    let geo = |f: &dyn Fn(GeoPoint) -> bool| any(v, |y| GeoPoint::from_bson(y).map_or(false, f));
+    Ok(match c {
+        Constraint::Equal(x) => equal(v, x),
+        Constraint::NotEqual(x) => !equal(v, x),
+        Constraint::LessThan(x) => cmp(x, |o| o == Ordering::Less),
+        Constraint::LessThanOrEqual(x) => cmp(x, |o| o != Ordering::Greater),
+        Constraint::GreaterThan(x) => cmp(x, |o| o == Ordering::Greater),
+        Constraint::GreaterThanOrEqual(x) => cmp(x, |o| o != Ordering::Less),
+        Constraint::In(xs) => xs.iter().any(|x| equal(v, x)),
+        Constraint::NotIn(xs) => !xs.iter().any(|x| equal(v, x)),
+        Constraint::Exists(x) => v.is_some() == *x,
+        Constraint::Regex(p, o) => match query::regex(p, o) {
+            Some(re) => any(v, |y| y.as_str().map_or(false, |s| re.is_match(s))),
+            None => false,
+        },
+        Constraint::InQuery(..) => return bad_request("Unexpected $inQuery"),
+        Constraint::NearSphere(p, d) => geo(&|y| d.map_or(true, |d| y.distance(p) <= d)),
+        Constraint::WithinBox(sw, ne) => geo(&|y| y.within_box(sw, ne)),
+        Constraint::WithinPolygon(ps) => geo(&|y| y.within_polygon(ps)),
+    })
+}
+
+/// Evaluate `filter` on stored `doc`, where `objectId` is the hex string of `_id`.
+fn matches(doc: &Document, filter: &Filter) -> Result<bool, Rejection> {
+    match filter {
+        Filter::And(v) => {
+            for f in v {
+                if !matches(doc, f)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        Filter::Or(v) => {
+            for f in v {
+                if matches(doc, f)? {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        Filter::Field(k, c) if k == OBJECT_ID => {
+            let id = oid_of(doc).map(|id| Bson::String(id.to_hex()));
+            satisfies(id.as_ref(), c)
+        }
+        Filter::Field(k, c) => satisfies(get_path(doc, k), c),
+        Filter::RelatedTo(..) => bad_request("Unexpected $relatedTo"),
+    }
+}
+
+/// Rank of types when sorting values of different types, same as MongoDB.
+fn rank(v: Option<&Bson>) -> u8 {
+    match v {
+        None | Some(Bson::Null) => 0,
+        Some(Bson::Int32(_)) | Some(Bson::Int64(_)) | Some(Bson::Double(_)) => 1,
+        Some(Bson::String(_)) => 2,
+        Some(Bson::Document(_)) => 3,
+        Some(Bson::Array(_)) => 4,
+        Some(Bson::ObjectId(_)) => 5,
+        Some(Bson::Boolean(_)) => 6,
+        Some(Bson::DateTime(_)) => 7,
+        Some(_) => 8,
+    }
+}
+
+/// Sort stored `docs` by `order`, where both `objectId` and `createdAt` are `_id`.
+fn sort(docs: &mut [Document], order: &[(String, Order)]) {
+    docs.sort_by(|a, b| {
+        order.iter().fold(Ordering::Equal, |ord, (k, o)| {
+            let k = if k == OBJECT_ID || k == CREATED_AT {
+                ID
+            } else {
+                k.as_str()
+            };
+            let (x, y) = (get_path(a, k), get_path(b, k));
+            ord.then_with(|| {
+                let ord = rank(x).cmp(&rank(y)).then_with(|| match (x, y) {
+                    (Some(x), Some(y)) => compare(x, y).unwrap_or(Ordering::Equal),
+                    _ => Ordering::Equal,
+                });
+                if *o == Order::Ascending {
+                    ord
+                } else {
+                    ord.reverse()
+                }
+            })
+        })
+    });
+}
+
+/// Entry of ACL of user with `uid` on `doc`, see `Access` for its encoding.
+fn acl_of<'a>(doc: &'a Document, uid: &str) -> Option<&'a Bson> {
+    doc.get_document(ACL).ok().and_then(|acl| acl.get(uid))
+}
+
+/// Entries of ACL of roles of user `t` on `doc`, or `None` if none of them is found.
+fn acl_of_roles<'a>(doc: &'a Document, t: &ClientToken) -> Option<Vec<&'a Bson>> {
+    let v: Vec<&Bson> = t
+        .roles
+        .iter()
+        .filter_map(|r| acl_of(doc, &role::key(r)))
+        .collect();
+    if v.is_empty() {
+        None
+    } else {
+        Some(v)
+    }
+}
+
+/// Same as `Mongodb::acl_filter`.
+fn granted(doc: &Document, user: &UserKind, kind: AccessKind) -> bool {
+    let public = || acl_of(doc, "*").map_or(true, |v| kind.granted_by(v));
+    match user {
+        UserKind::Master => true,
+        UserKind::Client(t) => match (acl_of(doc, &t.id), acl_of_roles(doc, t)) {
+            (Some(v), _) => kind.granted_by(v),
+            (None, Some(v)) => v.into_iter().any(|v| kind.granted_by(v)),
+            (None, None) => public(),
+        },
+        UserKind::Guest => public(),
+    }
+}
+
+fn oid_of(doc: &Document) -> Option<&ObjectId> {
+    doc.get_object_id(ID).ok()
+}
+
+/// Check unique fields of `doc` against other documents in `class`.
+fn unique_check(docs: &[Document], class: &str, doc: &Document) -> Result<(), Rejection> {
+    for (c, field) in UNIQUE.iter() {
+        if *c != class {
+            continue;
+        }
+        if let Some(v) = doc.get(field) {
+            let dup = docs
+                .iter()
+                .any(|d| oid_of(d) != oid_of(doc) && d.get(field) == Some(v));
+            if dup {
+                return conflict(format!("Duplicate {} in {}", field, class));
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Memory {
+    /// Create an empty in-memory database, where `url` is only used for logging.
+    pub async fn from_url(url: String) -> Self {
+        info!("in-memory database: {}", url);
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Database for Memory {
+    async fn create(
+        &self,
+        class: &str,
+        doc: Document,
+        _user: UserKind,
+    ) -> Result<Document, Rejection> {
+        let mut doc = resolve(doc)?;
+        update_doc(&mut doc, Utc::now());
+        doc.insert(ID, ObjectId::new());
+        trace!("create new {:?}: {:?}", class, doc);
+
+        let mut classes = self.classes.lock().unwrap();
+        let docs = classes.entry(class.to_string()).or_default();
+        unique_check(docs, class, &doc)?;
+        docs.push(doc.clone());
+        Ok(expose(doc))
+    }
+
+    async fn retrieve(
+        &self,
+        class: &str,
+        query: Query,
+        user: UserKind,
+    ) -> Result<Vec<Document>, Rejection> {
+        trace!("retrieve {:?} with query {:?}", class, query);
+        let filter = subqueries(self, class, query.filter, &user).await?;
+
+        let mut docs: Vec<Document> = vec![];
+        {
+            let classes = self.classes.lock().unwrap();
+            for d in classes.get(class).into_iter().flatten() {
+                if matches(d, &filter)? && granted(d, &user, AccessKind::Read) {
+                    docs.push(d.clone());
+                }
+            }
+        }
+        match near(&filter) {
+            Some((k, p)) if query.order.is_empty() => {
+                let distance = |d: &Document| {
+                    let points: Vec<GeoPoint> = match get_path(d, k) {
+                        Some(Bson::Array(a)) => a.iter().filter_map(GeoPoint::from_bson).collect(),
+                        Some(v) => GeoPoint::from_bson(v).into_iter().collect(),
+                        None => vec![],
+                    };
+                    points
+                        .iter()
+                        .map(|x| x.distance(p))
+                        .fold(f64::INFINITY, f64::min)
+                };
+                docs.sort_by(|a, b| {
+                    distance(a)
+                        .partial_cmp(&distance(b))
+                        .unwrap_or(Ordering::Equal)
+                });
+            }
+            _ => sort(&mut docs, &query.order),
+        }
+        let docs: Vec<Document> = docs
+            .into_iter()
+            .skip(query.skip as usize)
+            .take(query.limit.map_or(usize::MAX, |n| n as usize))
+            .map(|d| select(expose(d), &query.keys))
+            .collect();
+        trace!("retrieve result: {:?}", docs);
+        Ok(docs)
+    }
+
+    async fn count(&self, class: &str, filter: Filter, user: UserKind) -> Result<u64, Rejection> {
+        trace!("count {:?} with filter {:?}", class, filter);
+        let filter = subqueries(self, class, filter, &user).await?;
+
+        let classes = self.classes.lock().unwrap();
+        let mut n = 0;
+        for d in classes.get(class).into_iter().flatten() {
+            if matches(d, &filter)? && granted(d, &user, AccessKind::Read) {
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Update a document in specific class with id by user and return the documents before and after updating.
+    async fn update(
+        &self,
+        class: &str,
+        id: &str,
+        mut doc: Document,
+        user: UserKind,
+    ) -> Result<(Document, Document), Rejection> {
+        update_check(&mut doc, &user)?;
+        update_doc(&mut doc, Utc::now());
+        let ops = Operation::parse(doc)?;
+
+        let oid = ObjectId::with_string(id).map_or_else(|e| not_found("Object ID invalid"), Ok)?;
+        trace!("update {:?} by id {:?} with with {:?}", class, id, ops);
+
+        let mut classes = self.classes.lock().unwrap();
+        let docs = classes.entry(class.to_string()).or_default();
+        let i = docs
+            .iter()
+            .position(|d| oid_of(d) == Some(&oid) && granted(d, &user, AccessKind::Write));
+        match i {
+            Some(i) => {
+                let mut new = docs[i].clone();
+                for (k, op) in ops {
+                    apply(&mut new, &k, op)?;
+                }
+                unique_check(docs, class, &new)?;
+                let old = std::mem::replace(&mut docs[i], new.clone());
+                Ok((expose(old), expose(new)))
+            }
+            None => error::not_found("Object not found"),
+        }
+    }
+
+    async fn set_if_missing(
+        &self,
+        class: &str,
+        id: &str,
+        key: &str,
+        value: Bson,
+    ) -> Result<bool, Rejection> {
+        let oid = ObjectId::with_string(id).map_or_else(|_| not_found("Object ID invalid"), Ok)?;
+        let mut doc = doc! {key: value};
+        update_doc(&mut doc, Utc::now());
+        let ops = Operation::parse(doc)?;
+
+        let mut classes = self.classes.lock().unwrap();
+        let docs = classes.entry(class.to_string()).or_default();
+        match docs.iter_mut().find(|d| oid_of(d) == Some(&oid)) {
+            Some(d) if get_path(d, key).is_some() => Ok(false),
+            Some(d) => {
+                for (k, op) in ops {
+                    apply(d, &k, op)?;
+                }
+                Ok(true)
+            }
+            None => error::not_found("Object not found"),
+        }
+    }
+
+    async fn update_if(
+        &self,
+        class: &str,
+        id: &str,
+        key: &str,
+        expected: Bson,
+        mut doc: Document,
+    ) -> Result<bool, Rejection> {
+        let oid = ObjectId::with_string(id).map_or_else(|_| not_found("Object ID invalid"), Ok)?;
+        update_doc(&mut doc, Utc::now());
+        let ops = Operation::parse(doc)?;
+
+        let mut classes = self.classes.lock().unwrap();
+        let docs = classes.entry(class.to_string()).or_default();
+        match docs.iter_mut().find(|d| oid_of(d) == Some(&oid)) {
+            Some(d) if get_path(d, key) != Some(&expected) => Ok(false),
+            Some(d) => {
+                for (k, op) in ops {
+                    apply(d, &k, op)?;
+                }
+                Ok(true)
+            }
+            None => error::not_found("Object not found"),
+        }
+    }
+
+    async fn delete(&self, class: &str, id: &str, user: UserKind) -> Result<Document, Rejection> {
+        trace!("delete {:?} by id {:?}", class, id);
+        let oid = ObjectId::with_string(id).map_or_else(|e| not_found("Object ID invalid"), Ok)?;
+
+        let mut classes = self.classes.lock().unwrap();
+        let docs = classes.entry(class.to_string()).or_default();
+        let i = docs
+            .iter()
+            .position(|d| oid_of(d) == Some(&oid) && granted(d, &user, AccessKind::Delete));
+        match i {
+            Some(i) => Ok(expose(docs.remove(i))),
+            None => error::not_found("Object not found"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::database::tests::check_backend;
+
+    #[tokio::test]
+    async fn test_memory_backend() {
+        check_backend(Arc::new(Memory::default())).await;
+    }
+}
@@ -0,0 +1,830 @@
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::{SecondsFormat, Utc};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use regex::Regex;
+use rusqlite::{
+    functions::FunctionFlags,
+    types::{Value as SqlValue, ValueRef},
+    Connection, ErrorCode, Row,
+};
+use serde_json::{json, Map, Value};
+use warp::Rejection;
+
+use super::{
+    apply, get_path, near, resolve, select, subqueries, update_check, Database, Operation, ACL,
+    CREATED_AT, OBJECT_ID, UPDATED_AT,
+};
+use crate::{
+    acl::AccessKind,
+    error::{self, bad_request, conflict, internal_server_error},
+    query::{Constraint, Filter, Order, Query},
+    role,
+    types::{self, GeoPoint, Pointer},
+    user::{ClientToken, UserKind},
+};
+
+/// URL scheme that selects the SQLite backend, e.g. `sqlite://rhymer.db` or `sqlite://:memory:`.
+pub const SCHEME: &'static str = "sqlite://";
+
+/// All objects are stored in one table, with class data and ACL as JSON text.
+///
+/// Unique indexes, such as the one on username of `_User`, are the same as those created by
+/// `scripts/initdb.js`.
+const INIT: &'static str = "
+CREATE TABLE IF NOT EXISTS objects (
+    class TEXT NOT NULL,
+    id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    acl TEXT,
+    data TEXT NOT NULL,
+    PRIMARY KEY (class, id)
+);
+CREATE UNIQUE INDEX IF NOT EXISTS user_username
+    ON objects (json_extract(data, '$.username')) WHERE class = '_User';
+CREATE UNIQUE INDEX IF NOT EXISTS schema_class_name
+    ON objects (json_extract(data, '$.className')) WHERE class = '_SCHEMA';
+CREATE UNIQUE INDEX IF NOT EXISTS role_name
+    ON objects (json_extract(data, '$.name')) WHERE class = '_Role';
+CREATE UNIQUE INDEX IF NOT EXISTS session_token_id
+    ON objects (json_extract(data, '$.tokenId')) WHERE class = '_Session';
+";
+
+const COLUMNS: &'static str = "id, created_at, updated_at, acl, data";
+
+/// Database backed by SQLite, storing schemaless class data as JSON.
+#[derive(Debug, Clone)]
+pub struct Sqlite {
+    conn: Arc<Mutex<Connection>>,
+}
+
+/// SQL where clause and its parameters.
+type Clause = (String, Vec<SqlValue>);
+
+/// JSON path in SQLite of dot-separated `key`, e.g. `a.b` to `$."a"."b"`.
+fn json_path(key: &str) -> Result<String, Rejection> {
+    if key.contains('"') {
+        return bad_request(format!("Invalid field name {}", key));
+    }
+    let path: Vec<String> = key.split('.').map(|k| format!("\"{}\"", k)).collect();
+    Ok(format!("$.{}", path.join(".")))
+}
+
+/// Extended JSON of `v`, where dates are always `{"$date": ISO}` in milliseconds so that they
+/// are ordered as text.
+fn json(v: Bson) -> Value {
+    match v {
+        Bson::DateTime(t) => json!({"$date": t.to_rfc3339_opts(SecondsFormat::Millis, true)}),
+        Bson::Document(d) => Value::Object(d.into_iter().map(|(k, v)| (k, json(v))).collect()),
+        Bson::Array(a) => Value::Array(a.into_iter().map(json).collect()),
+        v => Value::from(v),
+    }
+}
+
+fn json_text(v: Bson) -> String {
+    json(v).to_string()
+}
+
+/// Same as `Mongodb::grant_filter`, where booleans are extracted as integers.
+fn grant_filter(key: &str, kind: AccessKind) -> Result<Clause, Rejection> {
+    let flags: Vec<String> = kind
+        .legacy_flags()
+        .iter()
+        .map(|f| format!("'{}'", f))
+        .collect();
+    let sql = format!(
+        "(json_extract(acl, ?) IN ({}) OR json_extract(acl, ?) = 1)",
+        flags.join(", ")
+    );
+    let field = json_path(&format!("{}.{}", key, kind.field()))?;
+    Ok((
+        sql,
+        vec![SqlValue::Text(json_path(key)?), SqlValue::Text(field)],
+    ))
+}
+
+/// Same as `Mongodb::role_filter`.
+fn role_filter(t: &ClientToken, kind: AccessKind, public: Clause) -> Result<Clause, Rejection> {
+    if t.roles.is_empty() {
+        return Ok(public);
+    }
+    let mut granted = vec![];
+    let mut missing = vec![];
+    let mut params = vec![];
+    let mut missing_params = vec![];
+    for r in t.roles.iter() {
+        let key = role::key(r);
+        let (sql, p) = grant_filter(&key, kind)?;
+        granted.push(sql);
+        params.extend(p);
+        missing.push("json_extract(acl, ?) IS NULL");
+        missing_params.push(SqlValue::Text(json_path(&key)?));
+    }
+    params.extend(missing_params);
+    params.extend(public.1);
+    let sql = format!(
+        "(({}) OR ({} AND {}))",
+        granted.join(" OR "),
+        missing.join(" AND "),
+        public.0
+    );
+    Ok((sql, params))
+}
+
+/// Same as `Mongodb::acl_filter`.
+fn acl_filter(user: &UserKind, kind: AccessKind) -> Result<Clause, Rejection> {
+    let (sql, params) = grant_filter("*", kind)?;
+    let public = (
+        format!("(json_extract(acl, '$.\"*\"') IS NULL OR {})", sql),
+        params,
+    );
+    Ok(match user {
+        UserKind::Master => ("1".to_string(), vec![]),
+        UserKind::Client(t) => {
+            let (sql, mut params) = grant_filter(&t.id, kind)?;
+            let (roles, role_params) = role_filter(t, kind, public)?;
+            params.push(SqlValue::Text(json_path(&t.id)?));
+            params.extend(role_params);
+            (
+                format!("({} OR (json_extract(acl, ?) IS NULL AND {}))", sql, roles),
+                params,
+            )
+        }
+        UserKind::Guest => public,
+    })
+}
+
+/// Type guard and SQL value of scalar `v` to compare with.
+fn scalar(v: &Bson) -> Option<(&'static str, SqlValue)> {
+    Some(match v {
+        Bson::String(s) => ("= 'text'", SqlValue::Text(s.clone())),
+        Bson::Boolean(b) => ("IN ('true', 'false')", SqlValue::Integer(*b as i64)),
+        Bson::Int32(i) => ("IN ('integer', 'real')", SqlValue::Integer(*i as i64)),
+        Bson::Int64(i) => ("IN ('integer', 'real')", SqlValue::Integer(*i)),
+        Bson::Double(f) => ("IN ('integer', 'real')", SqlValue::Real(*f)),
+        _ => return None,
+    })
+}
+
+/// Predicate that any of the values of field `key`, i.e. itself and its elements if it is an array,
+/// satisfies `cond`, which is given SQL expressions of the value and its JSON type.
+fn any(key: &str, cond: impl Fn(&str, &str) -> Clause) -> Result<Clause, Rejection> {
+    if key == OBJECT_ID {
+        return Ok(cond("objects.id", "'text'"));
+    }
+    let path = SqlValue::Text(json_path(key)?);
+    let (sql, params) = cond("json_each.value", "json_each.type");
+    let sql = format!(
+        "EXISTS (SELECT 1 FROM json_each(objects.data, ?) \
+         WHERE json_type(objects.data, ?) != 'object' AND {})",
+        sql
+    );
+    let mut p = vec![path.clone(), path];
+    p.extend(params);
+    Ok((sql, p))
+}
+
+fn compare(key: &str, op: &str, v: &Bson) -> Result<Clause, Rejection> {
+    if let Bson::DateTime(t) = v {
+        let date = Bson::String(t.to_rfc3339_opts(SecondsFormat::Millis, true));
+        return compare(&format!("{}.$date", key), op, &date);
+    }
+    match scalar(v) {
+        Some((guard, x)) => any(key, |v, t| {
+            (
+                format!("{} {} AND {} {} ?", t, guard, v, op),
+                vec![x.clone()],
+            )
+        }),
+        None => bad_request(format!("Cannot compare {} with {}", key, v)),
+    }
+}
+
+fn equal(key: &str, v: &Bson) -> Result<Clause, Rejection> {
+    let path = || json_path(key).map(SqlValue::Text);
+    Ok(match v {
+        Bson::Null if key == OBJECT_ID => ("0".to_string(), vec![]),
+        Bson::Null => (
+            "coalesce(json_type(objects.data, ?), 'null') = 'null'".to_string(),
+            vec![path()?],
+        ),
+        v => match scalar(v) {
+            Some(_) => compare(key, "=", v)?,
+            None if key == OBJECT_ID => ("0".to_string(), vec![]),
+            None => match Pointer::from_bson(v) {
+                Some(p) => pointer(key, &p)?,
+                None => (
+                    "json_extract(objects.data, ?) = json(?)".to_string(),
+                    vec![path()?, SqlValue::Text(json_text(v.clone()))],
+                ),
+            },
+        },
+    })
+}
+
+/// Predicate that field `key`, or any of its elements if it is an array, is a pointer to the
+/// same object as `p`, compared by class and id rather than JSON text as MongoDB does.
+fn pointer(key: &str, p: &Pointer) -> Result<Clause, Rejection> {
+    let path = json_path(key)?;
+    let field = |f: &str| SqlValue::Text(format!("{}.\"{}\"", path, f));
+    let sql = "(coalesce(json_extract(objects.data, ?) = 'Pointer' \
+         AND json_extract(objects.data, ?) = ? AND json_extract(objects.data, ?) = ?, 0) \
+         OR EXISTS (SELECT 1 FROM json_each(objects.data, ?) \
+         WHERE json_type(objects.data, ?) = 'array' \
+         AND json_extract(objects.data, json_each.fullkey || '.\"__type\"') = 'Pointer' \
+         AND json_extract(objects.data, json_each.fullkey || '.\"className\"') = ? \
+         AND json_extract(objects.data, json_each.fullkey || '.\"objectId\"') = ?))";
+    let (class, id) = (
+        SqlValue::Text(p.class_name.clone()),
+        SqlValue::Text(p.object_id.clone()),
+    );
+    Ok((
+        sql.to_string(),
+        vec![
+            field(types::TYPE),
+            field("className"),
+            class.clone(),
+            field("objectId"),
+            id.clone(),
+            SqlValue::Text(path.clone()),
+            SqlValue::Text(path),
+            class,
+            id,
+        ],
+    ))
+}
+
+fn not((sql, params): Clause) -> Clause {
+    (format!("NOT ({})", sql), params)
+}
+
+/// Join clauses by `op`, where empty clauses result in `empty`.
+fn join(v: Vec<Clause>, op: &str, empty: &str) -> Clause {
+    if v.is_empty() {
+        return (empty.to_string(), vec![]);
+    }
+    let (sql, params): (Vec<String>, Vec<Vec<SqlValue>>) = v.into_iter().unzip();
+    let sql: Vec<String> = sql.into_iter().map(|s| format!("({})", s)).collect();
+    (sql.join(op), params.into_iter().flatten().collect())
+}
+
+/// Translate `filter` into SQL, where `objectId` is the `id` column.
+fn inner_filter(filter: &Filter) -> Result<Clause, Rejection> {
+    Ok(match filter {
+        Filter::And(v) => join(
+            v.iter().map(inner_filter).collect::<Result<_, _>>()?,
+            " AND ",
+            "1",
+        ),
+        Filter::Or(v) => join(
+            v.iter().map(inner_filter).collect::<Result<_, _>>()?,
+            " OR ",
+            "0",
+        ),
+        Filter::Field(k, c) => match c {
+            Constraint::Equal(x) => equal(k, x)?,
+            Constraint::NotEqual(x) => not(equal(k, x)?),
+            Constraint::LessThan(x) => compare(k, "<", x)?,
+            Constraint::LessThanOrEqual(x) => compare(k, "<=", x)?,
+            Constraint::GreaterThan(x) => compare(k, ">", x)?,
+            Constraint::GreaterThanOrEqual(x) => compare(k, ">=", x)?,
+            Constraint::In(xs) => join(
+                xs.iter().map(|x| equal(k, x)).collect::<Result<_, _>>()?,
+                " OR ",
+                "0",
+            ),
+            Constraint::NotIn(xs) => not(join(
+                xs.iter().map(|x| equal(k, x)).collect::<Result<_, _>>()?,
+                " OR ",
+                "0",
+            )),
+            Constraint::Exists(x) if k == OBJECT_ID => {
+                ((if *x { "1" } else { "0" }).to_string(), vec![])
+            }
+            Constraint::Exists(x) => (
+                format!(
+                    "json_type(objects.data, ?) IS {}NULL",
+                    if *x { "NOT " } else { "" }
+                ),
+                vec![SqlValue::Text(json_path(k)?)],
+            ),
+            Constraint::Regex(p, o) => {
+                let re = if o.is_empty() {
+                    p.clone()
+                } else {
+                    format!("(?{}){}", o, p)
+                };
+                let re = SqlValue::Text(re);
+                any(k, |v, t| {
+                    (
+                        format!("{} = 'text' AND regexp(?, {})", t, v),
+                        vec![re.clone()],
+                    )
+                })?
+            }
+            Constraint::InQuery(..) => return bad_request("Unexpected $inQuery"),
+            Constraint::NearSphere(p, d) => {
+                let (sql, mut params) = distance(k, p)?;
+                match d {
+                    Some(d) => {
+                        params.push(SqlValue::Real(*d));
+                        (format!("{} <= ?", sql), params)
+                    }
+                    None => (format!("{} IS NOT NULL", sql), params),
+                }
+            }
+            Constraint::WithinBox(sw, ne) => (
+                "json_extract(objects.data, ?) = 'GeoPoint' \
+                 AND json_extract(objects.data, ?) BETWEEN ? AND ? \
+                 AND json_extract(objects.data, ?) BETWEEN ? AND ?"
+                    .to_string(),
+                vec![
+                    SqlValue::Text(format!("{}.\"{}\"", json_path(k)?, types::TYPE)),
+                    SqlValue::Text(format!("{}.\"coordinates\"[0]", json_path(k)?)),
+                    SqlValue::Real(sw.longitude),
+                    SqlValue::Real(ne.longitude),
+                    SqlValue::Text(format!("{}.\"coordinates\"[1]", json_path(k)?)),
+                    SqlValue::Real(sw.latitude),
+                    SqlValue::Real(ne.latitude),
+                ],
+            ),
+            Constraint::WithinPolygon(v) => {
+                let polygon: Vec<(f64, f64)> =
+                    v.iter().map(|p| (p.latitude, p.longitude)).collect();
+                (
+                    "coalesce(geo_within(json_extract(objects.data, ?), ?), 0)".to_string(),
+                    vec![
+                        SqlValue::Text(json_path(k)?),
+                        SqlValue::Text(json!(polygon).to_string()),
+                    ],
+                )
+            }
+        },
+        Filter::RelatedTo(..) => return bad_request("Unexpected $relatedTo"),
+    })
+}
+
+/// Distance in kilometers from geo point of field `key` to `p` in SQL, which is null if the field
+/// is not a geo point.
+fn distance(key: &str, p: &GeoPoint) -> Result<Clause, Rejection> {
+    Ok((
+        "geo_distance(json_extract(objects.data, ?), ?, ?)".to_string(),
+        vec![
+            SqlValue::Text(json_path(key)?),
+            SqlValue::Real(p.latitude),
+            SqlValue::Real(p.longitude),
+        ],
+    ))
+}
+
+/// Translate `order` into SQL, where `objectId`, `createdAt` and `updatedAt` are columns
+/// and ties are broken by insertion order.
+///
+/// Objects are sorted by distance to the point of `near` if `order` is empty.
+fn order_by(
+    order: &[(String, Order)],
+    near: Option<(&str, &GeoPoint)>,
+) -> Result<Clause, Rejection> {
+    let (mut sql, mut params) = (vec![], vec![]);
+    if let (true, Some((k, p))) = (order.is_empty(), near) {
+        let (s, p) = distance(k, p)?;
+        sql.push(s);
+        params.extend(p);
+    }
+    for (k, o) in order {
+        let dir = if *o == Order::Ascending {
+            "ASC"
+        } else {
+            "DESC"
+        };
+        match k.as_str() {
+            OBJECT_ID => sql.push(format!("id {}", dir)),
+            CREATED_AT => sql.push(format!("created_at {}", dir)),
+            UPDATED_AT => sql.push(format!("updated_at {}", dir)),
+            _ => {
+                sql.push(format!("json_extract(data, ?) {}", dir));
+                params.push(SqlValue::Text(json_path(k)?));
+            }
+        }
+    }
+    sql.push("rowid".to_string());
+    Ok((sql.join(", "), params))
+}
+
+/// `regexp(pattern, text)` function in SQL, where compiled pattern is cached by SQLite.
+fn create_regexp(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let re: Arc<Regex> = match ctx.get_aux(0)? {
+                Some(re) => re,
+                None => {
+                    let p = ctx.get::<String>(0)?;
+                    let re = Regex::new(&p)
+                        .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?;
+                    ctx.set_aux(0, re)?
+                }
+            };
+            Ok(ctx.get_raw(1).as_str().map_or(false, |s| re.is_match(s)))
+        },
+    )
+}
+
+/// Geo point stored as GeoJSON in JSON text `v`, e.g. extracted by `json_extract`.
+fn geo_point(v: ValueRef) -> Option<GeoPoint> {
+    let v = serde_json::from_str::<Value>(v.as_str().ok()?).ok()?;
+    GeoPoint::from_bson(&Bson::try_from(v).ok()?)
+}
+
+/// `geo_distance(point, latitude, longitude)` and `geo_within(point, polygon)` functions in SQL,
+/// where `polygon` is JSON text of `[[LATITUDE, LONGITUDE], ...]`. Both are null if `point` is
+/// not a geo point.
+fn create_geo(conn: &Connection) -> rusqlite::Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_scalar_function("geo_distance", 3, flags, |ctx| {
+        let p = GeoPoint::new(ctx.get(1)?, ctx.get(2)?);
+        Ok(geo_point(ctx.get_raw(0))
+            .zip(p)
+            .map(|(x, p)| x.distance(&p)))
+    })?;
+    conn.create_scalar_function("geo_within", 2, flags, |ctx| {
+        let polygon = serde_json::from_str::<Vec<(f64, f64)>>(&ctx.get::<String>(1)?)
+            .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?;
+        let polygon: Vec<GeoPoint> = polygon
+            .into_iter()
+            .filter_map(|(lat, lng)| GeoPoint::new(lat, lng))
+            .collect();
+        Ok(geo_point(ctx.get_raw(0)).map(|x| x.within_polygon(&polygon)))
+    })
+}
+
+/// Convert a row selected by `COLUMNS` into the form seen by clients.
+fn expose(row: &Row) -> rusqlite::Result<Document> {
+    let data: String = row.get(4)?;
+    let mut doc = serde_json::from_str::<Map<String, Value>>(&data)
+        .ok()
+        .and_then(|m| Document::try_from(m).ok())
+        .unwrap_or_default();
+    let acl: Option<String> = row.get(3)?;
+    if let Some(acl) = acl {
+        if let Some(acl) = serde_json::from_str::<Map<String, Value>>(&acl)
+            .ok()
+            .and_then(|m| Document::try_from(m).ok())
+        {
+            doc.insert(ACL, acl);
+        }
+    }
+    doc.insert(OBJECT_ID, row.get::<_, String>(0)?);
+    doc.insert(CREATED_AT, row.get::<_, String>(1)?);
+    doc.insert(UPDATED_AT, row.get::<_, String>(2)?);
+    Ok(doc)
+}
+
+/// Split `doc` into JSON text of ACL and data.
+fn split(mut doc: Document) -> (Option<String>, String) {
+    doc.remove(OBJECT_ID);
+    doc.remove(CREATED_AT);
+    doc.remove(UPDATED_AT);
+    let acl = doc.remove(ACL).map(json_text);
+    (acl, json_text(Bson::Document(doc)))
+}
+
+fn sql_error<T>(e: rusqlite::Error) -> Result<T, Rejection> {
+    match e {
+        rusqlite::Error::SqliteFailure(ref f, _) if f.code == ErrorCode::ConstraintViolation => {
+            conflict("Object conflicts with existing one")
+        }
+        e => {
+            error!("sqlite error {}", e);
+            internal_server_error("Unexpected query error")
+        }
+    }
+}
+
+/// Select the object of `id` in `class` and `filter` with `conn`.
+fn select_one(
+    conn: &Connection,
+    class: &str,
+    id: &str,
+    filter: Clause,
+) -> Result<Option<Document>, Rejection> {
+    let (sql, mut params) = filter;
+    let sql = format!(
+        "SELECT {} FROM objects WHERE class = ? AND id = ? AND {}",
+        COLUMNS, sql
+    );
+    params.insert(0, SqlValue::Text(class.to_string()));
+    params.insert(1, SqlValue::Text(id.to_string()));
+    let mut stmt = conn.prepare(&sql).or_else(sql_error)?;
+    let mut rows = stmt.query(params).or_else(sql_error)?;
+    let doc = match rows.next().or_else(sql_error)? {
+        Some(row) => Some(expose(row).or_else(sql_error)?),
+        None => None,
+    };
+    Ok(doc)
+}
+
+impl Sqlite {
+    /// Open SQLite database by url of form `sqlite://PATH`, where `sqlite://:memory:`
+    /// opens an in-memory one.
+    pub async fn from_url(url: String) -> Self {
+        let path = url.trim_start_matches(SCHEME);
+        let conn = if path == ":memory:" {
+            Connection::open_in_memory()
+        } else {
+            Connection::open(path)
+        }
+        .expect("failed to open sqlite database");
+        conn.execute_batch(INIT)
+            .expect("failed to initialize sqlite database");
+        create_regexp(&conn).expect("failed to create regexp function");
+        create_geo(&conn).expect("failed to create geo functions");
+        info!("sqlite database: {}", path);
+
+        Self {
+            conn: Arc::new(Mutex::new(conn)),
+        }
+    }
+
+    /// Run `f` with the connection on a thread where blocking is acceptable.
+    async fn run<T, F>(&self, f: F) -> Result<T, Rejection>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Rejection> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap()))
+            .await
+            .map_or_else(
+                |e| internal_server_error("Unexpected database error"),
+                |r| r,
+            )
+    }
+}
+
+#[async_trait]
+impl Database for Sqlite {
+    async fn create(
+        &self,
+        class: &str,
+        doc: Document,
+        _user: UserKind,
+    ) -> Result<Document, Rejection> {
+        let doc = resolve(doc)?;
+        let class = class.to_string();
+        let id = ObjectId::new();
+        let created_at = id.timestamp().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let updated_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        trace!("create new {:?}: {:?}", class, doc);
+
+        self.run(move |conn| {
+            let (acl, data) = split(doc);
+            conn.execute(
+                "INSERT INTO objects (class, id, created_at, updated_at, acl, data) VALUES (?, ?, ?, ?, ?, ?)",
+                vec![
+                    SqlValue::Text(class.clone()),
+                    SqlValue::Text(id.to_hex()),
+                    SqlValue::Text(created_at),
+                    SqlValue::Text(updated_at),
+                    acl.map_or(SqlValue::Null, SqlValue::Text),
+                    SqlValue::Text(data),
+                ],
+            )
+            .or_else(sql_error)?;
+            select_one(conn, &class, &id.to_hex(), ("1".to_string(), vec![]))?
+                .map_or_else(|| internal_server_error("Cannot create object"), Ok)
+        })
+        .await
+    }
+
+    async fn retrieve(
+        &self,
+        class: &str,
+        query: Query,
+        user: UserKind,
+    ) -> Result<Vec<Document>, Rejection> {
+        let filter = subqueries(self, class, query.filter, &user).await?;
+        let (filter_sql, filter_params) = inner_filter(&filter)?;
+        let (acl_sql, acl_params) = acl_filter(&user, AccessKind::Read)?;
+        let (order_sql, order_params) = order_by(&query.order, near(&filter))?;
+        let sql = format!(
+            "SELECT {} FROM objects WHERE class = ? AND {} AND {} ORDER BY {} LIMIT ? OFFSET ?",
+            COLUMNS, filter_sql, acl_sql, order_sql
+        );
+        let mut params = vec![SqlValue::Text(class.to_string())];
+        params.extend(filter_params);
+        params.extend(acl_params);
+        params.extend(order_params);
+        // Negative limit means no limit in SQLite.
+        let number = |n: u64| n.min(i64::MAX as u64) as i64;
+        params.push(SqlValue::Integer(query.limit.map_or(-1, number)));
+        params.push(SqlValue::Integer(number(query.skip)));
+        trace!("retrieve {:?} with {:?} {:?}", class, sql, params);
+
+        let keys = query.keys;
+        let docs = self
+            .run(move |conn| {
+                let mut stmt = conn.prepare(&sql).or_else(sql_error)?;
+                let rows = stmt
+                    .query_map(params, |row| expose(row).map(|d| select(d, &keys)))
+                    .or_else(sql_error)?;
+                rows.collect::<rusqlite::Result<Vec<Document>>>()
+                    .or_else(sql_error)
+            })
+            .await?;
+        trace!("retrieve result: {:?}", docs);
+        Ok(docs)
+    }
+
+    async fn count(&self, class: &str, filter: Filter, user: UserKind) -> Result<u64, Rejection> {
+        let filter = subqueries(self, class, filter, &user).await?;
+        let (filter_sql, filter_params) = inner_filter(&filter)?;
+        let (acl_sql, acl_params) = acl_filter(&user, AccessKind::Read)?;
+        let sql = format!(
+            "SELECT COUNT(*) FROM objects WHERE class = ? AND {} AND {}",
+            filter_sql, acl_sql
+        );
+        let mut params = vec![SqlValue::Text(class.to_string())];
+        params.extend(filter_params);
+        params.extend(acl_params);
+        trace!("count {:?} with {:?} {:?}", class, sql, params);
+
+        let n = self
+            .run(move |conn| {
+                conn.query_row(&sql, params, |row| row.get::<_, i64>(0))
+                    .or_else(sql_error)
+            })
+            .await?;
+        Ok(n as u64)
+    }
+
+    /// Update a document in specific class with id by user and return the documents before and after updating.
+    async fn update(
+        &self,
+        class: &str,
+        id: &str,
+        mut doc: Document,
+        user: UserKind,
+    ) -> Result<(Document, Document), Rejection> {
+        update_check(&mut doc, &user)?;
+        let ops = Operation::parse(doc)?;
+        let filter = acl_filter(&user, AccessKind::Write)?;
+        let (class, id) = (class.to_string(), id.to_string());
+        trace!("update {:?} by id {:?} with with {:?}", class, id, ops);
+
+        self.run(move |conn| {
+            let tx = conn.transaction().or_else(sql_error)?;
+            let old = match select_one(&tx, &class, &id, filter)? {
+                Some(old) => old,
+                None => return error::not_found("Object not found"),
+            };
+            let mut new = old.clone();
+            for (k, op) in ops {
+                apply(&mut new, &k, op)?;
+            }
+            let updated_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+            let (acl, data) = split(new.clone());
+            tx.execute(
+                "UPDATE objects SET updated_at = ?, acl = ?, data = ? WHERE class = ? AND id = ?",
+                vec![
+                    SqlValue::Text(updated_at.clone()),
+                    acl.map_or(SqlValue::Null, SqlValue::Text),
+                    SqlValue::Text(data),
+                    SqlValue::Text(class),
+                    SqlValue::Text(id),
+                ],
+            )
+            .or_else(sql_error)?;
+            tx.commit().or_else(sql_error)?;
+            new.insert(UPDATED_AT, updated_at);
+            Ok((old, new))
+        })
+        .await
+    }
+
+    async fn set_if_missing(
+        &self,
+        class: &str,
+        id: &str,
+        key: &str,
+        value: Bson,
+    ) -> Result<bool, Rejection> {
+        let ops = Operation::parse(doc! {key: value})?;
+        let filter = acl_filter(&UserKind::Master, AccessKind::Write)?;
+        let (class, id, key) = (class.to_string(), id.to_string(), key.to_string());
+        trace!("set {:?} of {:?} by id {:?} if missing", key, class, id);
+
+        self.run(move |conn| {
+            let tx = conn.transaction().or_else(sql_error)?;
+            let mut doc = match select_one(&tx, &class, &id, filter)? {
+                Some(d) if get_path(&d, &key).is_some() => return Ok(false),
+                Some(d) => d,
+                None => return error::not_found("Object not found"),
+            };
+            for (k, op) in ops {
+                apply(&mut doc, &k, op)?;
+            }
+            let updated_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+            let (_, data) = split(doc);
+            tx.execute(
+                "UPDATE objects SET updated_at = ?, data = ? WHERE class = ? AND id = ?",
+                vec![
+                    SqlValue::Text(updated_at),
+                    SqlValue::Text(data),
+                    SqlValue::Text(class),
+                    SqlValue::Text(id),
+                ],
+            )
+            .or_else(sql_error)?;
+            tx.commit().or_else(sql_error)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn update_if(
+        &self,
+        class: &str,
+        id: &str,
+        key: &str,
+        expected: Bson,
+        doc: Document,
+    ) -> Result<bool, Rejection> {
+        let ops = Operation::parse(doc)?;
+        let filter = acl_filter(&UserKind::Master, AccessKind::Write)?;
+        let (class, id, key) = (class.to_string(), id.to_string(), key.to_string());
+        trace!("update {:?} by id {:?} if {:?} matched", class, id, key);
+
+        self.run(move |conn| {
+            let tx = conn.transaction().or_else(sql_error)?;
+            let mut doc = match select_one(&tx, &class, &id, filter)? {
+                Some(d) if get_path(&d, &key) != Some(&expected) => return Ok(false),
+                Some(d) => d,
+                None => return error::not_found("Object not found"),
+            };
+            for (k, op) in ops {
+                apply(&mut doc, &k, op)?;
+            }
+            let updated_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+            let (_, data) = split(doc);
+            tx.execute(
+                "UPDATE objects SET updated_at = ?, data = ? WHERE class = ? AND id = ?",
+                vec![
+                    SqlValue::Text(updated_at),
+                    SqlValue::Text(data),
+                    SqlValue::Text(class),
+                    SqlValue::Text(id),
+                ],
+            )
+            .or_else(sql_error)?;
+            tx.commit().or_else(sql_error)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn delete(&self, class: &str, id: &str, user: UserKind) -> Result<Document, Rejection> {
+        trace!("delete {:?} by id {:?}", class, id);
+        let filter = acl_filter(&user, AccessKind::Delete)?;
+        let (class, id) = (class.to_string(), id.to_string());
+
+        self.run(move |conn| {
+            let tx = conn.transaction().or_else(sql_error)?;
+            let old = match select_one(&tx, &class, &id, filter)? {
+                Some(old) => old,
+                None => return error::not_found("Object not found"),
+            };
+            tx.execute(
+                "DELETE FROM objects WHERE class = ? AND id = ?",
+                vec![SqlValue::Text(class), SqlValue::Text(id)],
+            )
+            .or_else(sql_error)?;
+            tx.commit().or_else(sql_error)?;
+            Ok(old)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::database::tests::check_backend;
+
+    #[tokio::test]
+    async fn test_sqlite_backend() {
+        check_backend(Arc::new(
+            Sqlite::from_url("sqlite://:memory:".to_string()).await,
+        ))
+        .await;
+    }
+}
@@ -42,6 +42,7 @@ macro_rules! err {
 }
 
 err!(unauthorized);
+err!(forbidden);
 err!(bad_request);
 err!(internal_server_error);
 err!(not_found);
@@ -1,14 +1,19 @@
 use crate::{
-    acl::Acl,
-    database::{self, Database as _},
+    acl::{self, Access, Acl, CLIENT_ACL},
+    database::{self, Database as _, Operation},
     error::{self, internal_server_error},
+    query::{self, Constraint, Filter, Order},
+    role,
+    schema::{self, ClassOperation},
     server::{Context, Request},
-    user::UserKind,
+    session,
+    types::{self, GeoPoint, Pointer},
+    user::{self, UserKind},
     validator::ClassName,
 };
 use error::{bad_request, not_found};
-use mongodb::bson::{doc, Document};
-use std::{result::Result, sync::Arc};
+use mongodb::bson::{doc, Bson, Document};
+use std::{collections::HashMap, future::Future, result::Result, sync::Arc};
 use warp::{Rejection, Reply};
 
 /// Object instance.
@@ -17,7 +22,8 @@ pub struct Object {
     id: Option<String>,
     /// Data of this object.
     pub data: Document,
-    acl: Acl,
+    acl: Option<Acl>,
+    original: Option<Document>,
     ctx: Arc<Context>,
     user: UserKind,
 }
@@ -35,8 +41,12 @@ pub trait ObjectTrait {
 
     /// Retrieve this object by `id`.
     async fn get(&mut self, id: String) -> Result<Document, Rejection>;
-    /// Update it if id is set before, else create a new object.
+    /// Update it if id is set before, else create a new object, returning the object saved.
     async fn save(&mut self) -> Result<Document, Rejection>;
+    /// Get the object before the last update by `save`, if kept by the implementation.
+    fn original(&self) -> Option<&Document> {
+        None
+    }
     /// Destroy this object by id set before.
     async fn destroy(&mut self) -> Result<Document, Rejection>;
 }
@@ -49,11 +59,73 @@ impl Object {
 
     /// Set access control flags of this object.
     pub fn set_acl(&mut self, acl: Acl) {
-        self.acl = acl;
+        self.acl = Some(acl);
     }
-    /// Get access control flags of this object.
+    /// Get access control flags of this object, which is loaded by `get`.
     pub fn get_acl(&self) -> Acl {
-        self.acl.clone()
+        self.acl.clone().unwrap_or_default()
+    }
+
+    /// Add `amount` to number field `key` atomically on saving.
+    pub fn increment(&mut self, key: impl Into<String>, amount: impl Into<Bson>) {
+        self.data
+            .insert(key.into(), Operation::Increment(amount.into()));
+    }
+    /// Append `values` to array field `key` atomically on saving.
+    pub fn add<T: Into<Bson>>(
+        &mut self,
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = T>,
+    ) {
+        let values = values.into_iter().map(Into::into).collect();
+        self.data.insert(key.into(), Operation::Add(values));
+    }
+    /// Append `values` not in array field `key` yet atomically on saving.
+    pub fn add_unique<T: Into<Bson>>(
+        &mut self,
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = T>,
+    ) {
+        let values = values.into_iter().map(Into::into).collect();
+        self.data.insert(key.into(), Operation::AddUnique(values));
+    }
+    /// Remove all occurrences of `values` from array field `key` atomically on saving.
+    pub fn remove<T: Into<Bson>>(
+        &mut self,
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = T>,
+    ) {
+        let values = values.into_iter().map(Into::into).collect();
+        self.data.insert(key.into(), Operation::Remove(values));
+    }
+    /// Remove field `key` on saving.
+    pub fn unset(&mut self, key: impl Into<String>) {
+        self.data.insert(key.into(), Operation::Delete);
+    }
+    /// Add objects referenced by `pointers` to relation field `key` on saving.
+    pub fn add_relation(
+        &mut self,
+        key: impl Into<String>,
+        pointers: impl IntoIterator<Item = Pointer>,
+    ) {
+        let pointers = pointers.into_iter().collect();
+        self.data
+            .insert(key.into(), Operation::AddRelation(pointers));
+    }
+    /// Remove objects referenced by `pointers` from relation field `key` on saving.
+    pub fn remove_relation(
+        &mut self,
+        key: impl Into<String>,
+        pointers: impl IntoIterator<Item = Pointer>,
+    ) {
+        let pointers = pointers.into_iter().collect();
+        self.data
+            .insert(key.into(), Operation::RemoveRelation(pointers));
+    }
+
+    /// Pointer to this object, which is `None` if id is not set.
+    pub fn pointer(&self) -> Option<Pointer> {
+        self.id.clone().map(|id| Pointer::new(&self.class, id))
     }
 }
 
@@ -66,7 +138,8 @@ impl ObjectTrait for Object {
             id: None,
             class: "".to_string(),
             data: Document::default(),
-            acl: Acl::default(),
+            acl: None,
+            original: None,
         }
     }
     fn set_id(&mut self, id: impl Into<String>) {
@@ -82,13 +155,14 @@ impl ObjectTrait for Object {
         let filter = doc! {database::OBJECT_ID: &id };
         let result = (*self.ctx)
             .db
-            .retrieve(&self.class, filter, self.user.clone())
+            .retrieve(&self.class, filter.into(), self.user.clone())
             .await;
         result.map_or_else(
             |e| Err(e),
             |v| {
                 if let Some(v) = v.first() {
                     self.data = v.clone();
+                    self.acl = Some(acl::of(v));
                     self.id = Some(id);
                     Ok(v.to_owned())
                 // serde_json::to_string(v)
@@ -106,34 +180,60 @@ impl ObjectTrait for Object {
     async fn save(&mut self) -> Result<Document, Rejection> {
         let mut doc = self.data.clone();
 
-        match self.user {
-            // Only Master can modify ACL.
-            UserKind::Master => {
-                let acl_doc: Document = self.acl.clone().into();
-                doc.insert(database::ACL, acl_doc);
+        match (&self.user, &self.acl) {
+            // ACL of Master defaults to read-write by all users, while others save it only if set.
+            (UserKind::Master, acl) => {
+                let acl = acl.clone().unwrap_or_default();
+                doc.insert(database::ACL, Document::from(acl));
+            }
+            (_, Some(acl)) => {
+                doc.insert(database::ACL, Document::from(acl.clone()));
             }
             _ => {}
         };
+        // Passwords of users are stored as hashes only, however saved.
+        let pwd = self.class == "_User" && user::hash_password_of(&mut doc).await?;
+        let added = schema::validate(
+            &self.ctx,
+            &self.class,
+            &mut doc,
+            self.id.is_none(),
+            &self.user,
+        )
+        .await?;
 
-        if let Some(id) = self.id.clone() {
-            (*self.ctx)
+        let saved = if let Some(id) = self.id.clone() {
+            let (old, new) = (*self.ctx)
                 .db
                 .update(&self.class, &id, doc, self.user.clone())
-                .await
-        // Database guarantees the invariance of id, thus no need to update self.id
+                .await?;
+            if pwd {
+                session::revoke_others(&self.ctx, &id, &self.user).await?;
+            }
+            // Database guarantees the invariance of id, thus no need to update self.id
+            self.original = Some(old);
+            new
         } else {
-            (*self.ctx)
+            let d = (*self.ctx)
                 .db
                 .create(&self.class, doc, self.user.clone())
-                .await
-                .map(|d| {
-                    let id = d
-                        .get(database::OBJECT_ID)
-                        .expect("create should return objectId");
-                    self.id = Some(id.to_string());
-                    d
-                })
+                .await?;
+            let id = d
+                .get_str(database::OBJECT_ID)
+                .expect("create should return objectId");
+            self.id = Some(id.to_string());
+            d
+        };
+        if self.class == role::ROLE {
+            self.ctx.sessions.clear_roles();
         }
+        // Fields inferred are recorded only if written.
+        schema::record(&self.ctx, &self.class, added).await?;
+        Ok(saved)
+    }
+
+    fn original(&self) -> Option<&Document> {
+        self.original.as_ref()
     }
 
     async fn destroy(&mut self) -> Result<Document, Rejection> {
@@ -144,6 +244,9 @@ impl ObjectTrait for Object {
                 .await
                 .map(|d| {
                     self.id = None;
+                    if self.class == role::ROLE {
+                        self.ctx.sessions.clear_roles();
+                    }
                     d
                 })
         } else {
@@ -152,13 +255,421 @@ impl ObjectTrait for Object {
     }
 }
 
+/// Number of objects fetched at a time by `Query::each`.
+const EACH_BATCH_SIZE: u64 = 100;
+
+/// Query on objects of a class, performed with permissions of the user it is created from.
+///
+/// Conditions added by chaining are all required to hold, e.g.
+/// `ctx.query("Song").greater_than("score", 10).order_by("score", Order::Descending).limit(10).find()`.
+pub struct Query {
+    class: String,
+    query: query::Query,
+    filters: Vec<Filter>,
+    include: Vec<String>,
+    error: Option<String>,
+    ctx: Arc<Context>,
+    user: UserKind,
+}
+
+impl Query {
+    /// Create a query on all objects from current server context and the requesting user.
+    pub fn from_context(ctx: Arc<Context>, user: UserKind) -> Self {
+        Query {
+            ctx,
+            user,
+            class: "".to_string(),
+            query: query::Query::default(),
+            filters: vec![],
+            include: vec![],
+            error: None,
+        }
+    }
+
+    /// Set class of objects to query.
+    pub fn set_class(&mut self, name: impl Into<String>) {
+        self.class = name.into()
+    }
+
+    fn constraint(mut self, key: impl Into<String>, c: Constraint) -> Self {
+        self.filters.push(Filter::Field(key.into(), c));
+        self
+    }
+
+    /// Field `key` equals to `value`, or contains it if the field is an array.
+    pub fn equal_to(self, key: impl Into<String>, value: impl Into<Bson>) -> Self {
+        self.constraint(key, Constraint::Equal(value.into()))
+    }
+    /// Field `key` does not equal to `value`.
+    pub fn not_equal_to(self, key: impl Into<String>, value: impl Into<Bson>) -> Self {
+        self.constraint(key, Constraint::NotEqual(value.into()))
+    }
+    /// Field `key` is less than `value`.
+    pub fn less_than(self, key: impl Into<String>, value: impl Into<Bson>) -> Self {
+        self.constraint(key, Constraint::LessThan(value.into()))
+    }
+    /// Field `key` is less than or equal to `value`.
+    pub fn less_than_or_equal_to(self, key: impl Into<String>, value: impl Into<Bson>) -> Self {
+        self.constraint(key, Constraint::LessThanOrEqual(value.into()))
+    }
+    /// Field `key` is greater than `value`.
+    pub fn greater_than(self, key: impl Into<String>, value: impl Into<Bson>) -> Self {
+        self.constraint(key, Constraint::GreaterThan(value.into()))
+    }
+    /// Field `key` is greater than or equal to `value`.
+    pub fn greater_than_or_equal_to(self, key: impl Into<String>, value: impl Into<Bson>) -> Self {
+        self.constraint(key, Constraint::GreaterThanOrEqual(value.into()))
+    }
+    /// Field `key` equals to any of `values`.
+    pub fn contained_in<T: Into<Bson>>(
+        self,
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = T>,
+    ) -> Self {
+        let values = values.into_iter().map(Into::into).collect();
+        self.constraint(key, Constraint::In(values))
+    }
+    /// Field `key` equals to none of `values`.
+    pub fn not_contained_in<T: Into<Bson>>(
+        self,
+        key: impl Into<String>,
+        values: impl IntoIterator<Item = T>,
+    ) -> Self {
+        let values = values.into_iter().map(Into::into).collect();
+        self.constraint(key, Constraint::NotIn(values))
+    }
+    /// Field `key` exists if `exists` is true, else it does not exist.
+    pub fn exists(self, key: impl Into<String>, exists: bool) -> Self {
+        self.constraint(key, Constraint::Exists(exists))
+    }
+    /// Field `key` is a string matching regular expression `pattern` with `options`,
+    /// which consists of `i`, `m`, `s` and `x`.
+    ///
+    /// Invalid pattern or options results in an error when the query is performed.
+    pub fn matches(mut self, key: impl Into<String>, pattern: &str, options: &str) -> Self {
+        let key = key.into();
+        let valid =
+            options.chars().all(|c| "imsx".contains(c)) && query::regex(pattern, options).is_some();
+        if !valid {
+            self.error = Some(format!("Invalid regex of {}", key));
+        }
+        self.constraint(
+            key,
+            Constraint::Regex(pattern.to_string(), options.to_string()),
+        )
+    }
+    /// Field `key` points to any object in `class` satisfying `filter`.
+    pub fn matches_query(self, key: impl Into<String>, class: &str, filter: Filter) -> Self {
+        self.constraint(
+            key,
+            Constraint::InQuery(class.to_string(), Box::new(filter)),
+        )
+    }
+    /// Geo point field `key` is near `point`, sorted from the nearest to the farthest
+    /// unless sorted by other fields.
+    pub fn near(self, key: impl Into<String>, point: GeoPoint) -> Self {
+        self.constraint(key, Constraint::NearSphere(point, None))
+    }
+    /// Geo point field `key` is within `km` kilometers of `point`, sorted the same as `near`.
+    pub fn within_kilometers(self, key: impl Into<String>, point: GeoPoint, km: f64) -> Self {
+        self.constraint(key, Constraint::NearSphere(point, Some(km)))
+    }
+    /// Geo point field `key` is within the box of `southwest` and `northeast` corners.
+    pub fn within_geo_box(
+        self,
+        key: impl Into<String>,
+        southwest: GeoPoint,
+        northeast: GeoPoint,
+    ) -> Self {
+        self.constraint(key, Constraint::WithinBox(southwest, northeast))
+    }
+    /// Geo point field `key` is within the polygon of `vertices`.
+    pub fn within_polygon(self, key: impl Into<String>, vertices: Vec<GeoPoint>) -> Self {
+        self.constraint(key, Constraint::WithinPolygon(vertices))
+    }
+    /// Objects are in relation field `key` of the object referenced by `pointer`.
+    pub fn related_to(mut self, pointer: Pointer, key: impl Into<String>) -> Self {
+        self.filters.push(Filter::RelatedTo(pointer, key.into()));
+        self
+    }
+    /// Objects satisfy `filter`, such as those parsed by `Filter::from_json`.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Sort by `key` after the fields already specified.
+    pub fn order_by(mut self, key: impl Into<String>, order: Order) -> Self {
+        self.query = self.query.order_by(key, order);
+        self
+    }
+    /// Return at most `n` objects.
+    pub fn limit(mut self, n: u64) -> Self {
+        self.query = self.query.limit(n);
+        self
+    }
+    /// Skip the first `n` objects.
+    pub fn skip(mut self, n: u64) -> Self {
+        self.query = self.query.skip(n);
+        self
+    }
+    /// Only return fields of `keys`, as well as `objectId`, `createdAt`, `updatedAt` and `acl`.
+    pub fn select<T: Into<String>>(mut self, keys: impl IntoIterator<Item = T>) -> Self {
+        self.query = self.query.select(keys);
+        self
+    }
+    /// Replace pointers in fields of `keys` by the objects referenced, such as `post` and
+    /// `post.author`, where objects invisible to the user are left as pointers.
+    pub fn include<T: Into<String>>(mut self, keys: impl IntoIterator<Item = T>) -> Self {
+        self.include.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    async fn build(&self) -> Result<query::Query, Rejection> {
+        if let Some(e) = self.error.clone() {
+            return bad_request(e);
+        }
+        let filter = Filter::And(self.filters.clone());
+        filter.check_near(true)?;
+        let query = self.query.clone().filter(filter);
+        schema::check_query(&self.ctx, &self.class, &query, &self.user).await?;
+        Ok(query)
+    }
+
+    /// Retrieve all objects matched.
+    pub async fn find(&self) -> Result<Vec<Document>, Rejection> {
+        let query = self.build().await?;
+        let mut v = self
+            .ctx
+            .db
+            .retrieve(&self.class, query, self.user.clone())
+            .await?;
+        include(&self.ctx, &self.user, &mut v, &self.include).await?;
+        Ok(v)
+    }
+
+    /// Retrieve the first object matched.
+    pub async fn first(&self) -> Result<Option<Document>, Rejection> {
+        let query = self.build().await?.limit(1);
+        let mut v = self
+            .ctx
+            .db
+            .retrieve(&self.class, query, self.user.clone())
+            .await?;
+        include(&self.ctx, &self.user, &mut v, &self.include).await?;
+        Ok(v.into_iter().next())
+    }
+
+    /// Count objects matched, regardless of `skip` and `limit`.
+    pub async fn count(&self) -> Result<u64, Rejection> {
+        let query = self.build().await?;
+        self.ctx
+            .db
+            .count(&self.class, query.filter, self.user.clone())
+            .await
+    }
+
+    /// Call `f` on each object matched in batches, in the order of `objectId`,
+    /// regardless of `order_by`, `skip` and `limit`.
+    ///
+    /// Iteration stops at the first error returned by `f`.
+    pub async fn each<F, Fut>(&self, mut f: F) -> Result<(), Rejection>
+    where
+        F: FnMut(Document) -> Fut,
+        Fut: Future<Output = Result<(), Rejection>>,
+    {
+        let filter = self.build().await?.filter;
+        let mut last: Option<String> = None;
+        loop {
+            let mut filters = vec![filter.clone()];
+            if let Some(id) = last.take() {
+                let c = Constraint::GreaterThan(Bson::String(id));
+                filters.push(Filter::Field(database::OBJECT_ID.to_string(), c));
+            }
+            let query = query::Query {
+                filter: Filter::And(filters),
+                order: vec![(database::OBJECT_ID.to_string(), Order::Ascending)],
+                skip: 0,
+                limit: Some(EACH_BATCH_SIZE),
+                keys: self.query.keys.clone(),
+            };
+            let mut v = self
+                .ctx
+                .db
+                .retrieve(&self.class, query, self.user.clone())
+                .await?;
+            include(&self.ctx, &self.user, &mut v, &self.include).await?;
+            let n = v.len() as u64;
+            for d in v {
+                last = d.get_str(database::OBJECT_ID).ok().map(String::from);
+                f(d).await?;
+            }
+            if n < EACH_BATCH_SIZE || last.is_none() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Call `f` on each value of field `path` in `doc`, or each element of it if it is an array.
+fn each_at(doc: &mut Document, path: &[&str], f: &mut impl FnMut(&mut Bson)) {
+    match path {
+        [] => {}
+        [k] => match doc.get_mut(k) {
+            Some(Bson::Array(a)) => a.iter_mut().for_each(f),
+            Some(v) => f(v),
+            None => {}
+        },
+        [k, rest @ ..] => match doc.get_mut(k) {
+            Some(Bson::Document(d)) => each_at(d, rest, f),
+            Some(Bson::Array(a)) => {
+                for v in a.iter_mut() {
+                    if let Bson::Document(d) = v {
+                        each_at(d, rest, f);
+                    }
+                }
+            }
+            _ => {}
+        },
+    }
+}
+
+/// Replace pointers in fields of `keys` of `docs` by the objects referenced, which are
+/// retrieved on behalf of `user` and marked by `{"__type": "Object", "className": CLASS}`.
+///
+/// Nested fields such as `a.b` are resolved after their parents, which are included as well.
+/// Pointers to built-in classes not reachable by `user`, or to classes whose objects `user` is
+/// not allowed to get by class-level permissions, are left as is, see `schema::reachable`.
+async fn include(
+    ctx: &Context,
+    user: &UserKind,
+    docs: &mut [Document],
+    keys: &[String],
+) -> Result<(), Rejection> {
+    let mut paths: Vec<Vec<&str>> = vec![];
+    for k in keys {
+        let path: Vec<&str> = k.split('.').collect();
+        for i in 1..=path.len() {
+            if !paths.contains(&path[..i].to_vec()) {
+                paths.push(path[..i].to_vec());
+            }
+        }
+    }
+    paths.sort_by_key(|p| p.len());
+
+    for path in paths {
+        let mut ids: HashMap<String, Vec<Bson>> = HashMap::new();
+        for d in docs.iter_mut() {
+            each_at(d, &path, &mut |v: &mut Bson| {
+                if let Some(p) = Pointer::from_bson(v) {
+                    ids.entry(p.class_name)
+                        .or_default()
+                        .push(p.object_id.into());
+                }
+            });
+        }
+
+        let mut objects: HashMap<Pointer, Document> = HashMap::new();
+        for (class, ids) in ids {
+            if schema::reachable(&class, user).is_err()
+                || !schema::allows(ctx, &class, ClassOperation::Get, user).await?
+            {
+                continue;
+            }
+            let filter = Filter::Field(database::OBJECT_ID.to_string(), Constraint::In(ids));
+            for d in ctx.db.retrieve(&class, filter.into(), user.clone()).await? {
+                let id = match d.get_str(database::OBJECT_ID) {
+                    Ok(id) => id.to_string(),
+                    Err(_) => continue,
+                };
+                let mut d = schema::protect(ctx, &class, user, d);
+                d.insert(types::TYPE, "Object");
+                d.insert("className", class.clone());
+                objects.insert(Pointer::new(&class, id), d);
+            }
+        }
+
+        for d in docs.iter_mut() {
+            each_at(d, &path, &mut |v: &mut Bson| {
+                if let Some(o) = Pointer::from_bson(v).and_then(|p| objects.get(&p)) {
+                    *v = Bson::Document(o.clone());
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Take ACL set by `user` from `body` of a request to create, or update object `id` of `class`.
+///
+/// Changing ACL of an existing object requires permission to delete it as well as to update,
+/// lest users allowed to update only grant themselves more. With `Server::restrict_acl_grants`,
+/// no entry may grant more than the access of `user` on the object, i.e. that by its current
+/// ACL when updating, or by the new one when creating.
+pub(crate) async fn take_acl(
+    ctx: &Context,
+    class: &str,
+    id: Option<&str>,
+    body: &mut Document,
+    user: &UserKind,
+) -> Result<Option<Acl>, Rejection> {
+    if !matches!(user, UserKind::Master) {
+        let prefix = format!("{}.", database::ACL);
+        if body
+            .keys()
+            .any(|k| k == database::ACL || k.starts_with(&prefix))
+        {
+            return bad_request(format!("Cannot update ACL but by {}", CLIENT_ACL));
+        }
+    }
+    let acl = match body.remove(CLIENT_ACL) {
+        Some(v) => acl::parse(v)?,
+        None => return Ok(None),
+    };
+    let own = match id {
+        _ if matches!(user, UserKind::Master) => Access::ALL,
+        Some(id) => {
+            let filter = doc! {database::OBJECT_ID: id};
+            let v = ctx
+                .db
+                .retrieve(class, filter.into(), UserKind::Master)
+                .await?;
+            v.first()
+                .map_or(Access::NONE, |d| acl::of(d).access_of(user))
+        }
+        None => acl.access_of(user),
+    };
+    // Updating fails anyway if not writable, as if the object is not found.
+    if id.is_some() && own.write && !own.delete {
+        return error::forbidden("Cannot change ACL without permission to delete");
+    }
+    if ctx.restrict_acl_grants {
+        let mut granted = acl.user.values().chain(std::iter::once(&acl.other));
+        if (id.is_none() || own.write) && !granted.all(|a| own.includes(*a)) {
+            return error::forbidden("Cannot grant permissions beyond your own");
+        }
+    }
+    Ok(Some(acl))
+}
+
 /// Create an object in specific class, used by RESTFul API.
+///
+/// ACL of the object can be set by `ACL` field, see `take_acl`, or defaults to that set by
+/// `Server::set_default_acl` for the class, either of which is moved into `req.acl` before
+/// before save hooks, and the object is saved with whatever they leave there.
 pub async fn create(
     class: ClassName,
     mut req: Request,
     ctx: Arc<Context>,
 ) -> Result<impl Reply, Rejection> {
     let class = class.as_str();
+    schema::authorize(&ctx, class, ClassOperation::Create, &req.user).await?;
+    if let Some(body) = req.body.as_mut() {
+        req.acl = take_acl(&ctx, class, None, body, &req.user).await?;
+    }
+    if req.acl.is_none() {
+        req.acl = ctx.default_acl.get(class).map(|t| t.apply(&req.user));
+    }
     if let Some(f) = ctx.before_save.get(class) {
         trace!("before save(create): {}", class);
         req = f(req, ctx.clone()).await?;
@@ -167,13 +678,15 @@ pub async fn create(
         let mut obj = Object::from_context(ctx.clone(), req.user.clone());
         obj.set_class(class);
         obj.set_data(body.clone()); // FIXME: maybe after hook do not need body?
+        if let Some(acl) = req.acl.clone() {
+            obj.set_acl(acl);
+        }
 
-        let result = obj.save().await.and_then(|d| {
-            serde_json::to_string(&d)
-                .map_or_else(|_e| internal_server_error("Serialization error"), |s| Ok(s))
-        })?;
+        let d = obj.save().await?;
+        let result = types::to_json(schema::protect(&ctx, class, &req.user, d.clone()))?;
         if let Some(f) = ctx.after_save.get(class) {
             trace!("after save(create): {}", class);
+            req.object = Some(d);
             req = f(req, ctx.clone()).await?;
         };
         Ok(warp::reply::with_status(
@@ -185,35 +698,89 @@ pub async fn create(
     }
 }
 
-/// TODO: Query instead of directly query db.
+/// Limit of queries from clients if `Config::max_limit` is not set, the same as the default
+/// page size of Parse.
+const MAX_LIMIT: u64 = 100;
+
+/// Retrieve objects in class by query string such as `where={"score":{"$gte":10}}&limit=10`,
+/// used by RESTFul API.
+///
+/// At most `Config::max_limit` objects are returned. With `count=1`, the response is
+/// `{"results": [...], "count": N}` where `N` is the number of all objects matched,
+/// and `results` is empty if `limit=0`. Pointers in comma-separated fields of `include`
+/// are replaced by the objects referenced.
 pub async fn retrieve_by_filter(
     class: ClassName,
-    filter: Document,
+    mut q: HashMap<String, String>,
     req: Request,
     ctx: Arc<Context>,
 ) -> Result<impl Reply, Rejection> {
-    let result = ctx.db.retrieve(class.as_str(), filter, req.user).await;
-    result.and_then(|v| {
-        serde_json::to_string(&v)
-            .map_or_else(|_e| internal_server_error("Serialization error"), |s| Ok(s))
-    })
+    let count = query::flag(&q, "count")?;
+    q.remove("count");
+    let keys = q.remove("include").map(|v| query::fields(&v)).transpose()?;
+    let keys = keys.unwrap_or_default();
+    let mut query = query::Query::from_query(q)?;
+    schema::check_query(&ctx, class.as_str(), &query, &req.user).await?;
+    let max = match ctx.config.max_limit {
+        0 => MAX_LIMIT,
+        n => n,
+    };
+    if max < u64::MAX {
+        query.limit = Some(query.limit.map_or(max, |n| n.min(max)));
+    }
+
+    let class = class.as_str();
+    let protect = |v: Vec<Document>| -> Vec<Document> {
+        v.into_iter()
+            .map(|d| schema::protect(&ctx, class, &req.user, d))
+            .collect()
+    };
+    if count {
+        schema::authorize(&ctx, class, ClassOperation::Count, &req.user).await?;
+    }
+    if !count || query.limit != Some(0) {
+        schema::authorize(&ctx, class, ClassOperation::Find, &req.user).await?;
+    }
+    let result = if count {
+        let n = ctx
+            .db
+            .count(class, query.filter.clone(), req.user.clone())
+            .await?;
+        let mut v = match query.limit {
+            Some(0) => vec![],
+            _ => ctx.db.retrieve(class, query, req.user.clone()).await?,
+        };
+        include(&ctx, &req.user, &mut v, &keys).await?;
+        Bson::from(doc! {"results": protect(v), "count": n as i64})
+    } else {
+        let mut v = ctx.db.retrieve(class, query, req.user.clone()).await?;
+        include(&ctx, &req.user, &mut v, &keys).await?;
+        Bson::from(protect(v))
+    };
+    types::to_json(result)
 }
 
-/// TODO: use Query instead of directly query db.
+/// Retrieve an object in class by id, used by RESTFul API.
+///
+/// Pointers in comma-separated fields of `include` are replaced by the objects referenced.
 pub async fn retrieve(
     class: ClassName,
     id: String,
+    mut q: HashMap<String, String>,
     req: Request,
     ctx: Arc<Context>,
 ) -> Result<impl Reply, Rejection> {
-    let filter = doc! {database::OBJECT_ID: id };
-    let result = ctx.db.retrieve(class.as_str(), filter, req.user).await;
+    schema::authorize(&ctx, class.as_str(), ClassOperation::Get, &req.user).await?;
+    let keys = q.remove("include").map(|v| query::fields(&v)).transpose()?;
+    let mut query = Query::from_context(ctx.clone(), req.user.clone());
+    query.set_class(class.as_str());
+    let query = query.include(keys.unwrap_or_default());
+    let result = query.equal_to(database::OBJECT_ID, id).find().await;
     result.map_or_else(
         |e| Err(e),
         |v| {
             if let Some(v) = v.first() {
-                serde_json::to_string(v)
-                    .map_or_else(|_e| internal_server_error("Serialization error"), |s| Ok(s))
+                types::to_json(schema::protect(&ctx, class.as_str(), &req.user, v.clone()))
             } else if v.len() == 0 {
                 not_found("Object not found")
             } else {
@@ -224,13 +791,24 @@ pub async fn retrieve(
 }
 
 /// Update an object with id in class, used by RESTFul API.
+///
+/// The response is the object updated, or `{"original": ..., "updated": ...}` with both
+/// the objects before and after updating if `original=1` is in query string. ACL of the object
+/// can be replaced by `ACL` field, see `take_acl`, which is moved into `req.acl` before before
+/// save hooks as when creating.
 pub async fn update(
     class: ClassName,
     id: String,
+    q: HashMap<String, String>,
     mut req: Request,
     ctx: Arc<Context>,
 ) -> Result<impl Reply, Rejection> {
+    let original = query::flag(&q, "original")?;
     let class = class.as_str();
+    schema::authorize(&ctx, class, ClassOperation::Update, &req.user).await?;
+    if let Some(body) = req.body.as_mut() {
+        req.acl = take_acl(&ctx, class, Some(id.as_str()), body, &req.user).await?;
+    }
     if let Some(f) = ctx.before_save.get(class) {
         trace!("before save(update): {}", class);
         req = f(req, ctx.clone()).await?;
@@ -240,14 +818,26 @@ pub async fn update(
         obj.set_class(class);
         obj.set_id(id);
         obj.set_data(body.clone());
+        if let Some(acl) = req.acl.clone() {
+            obj.set_acl(acl);
+        }
 
-        let result = obj.save().await.and_then(|d| {
-            serde_json::to_string(&d)
-                .map_or_else(|_e| internal_server_error("Serialization error"), |s| Ok(s))
-        })?;
+        let d = obj.save().await?;
+        let old = obj.original().cloned();
+        let protect = |d: Document| schema::protect(&ctx, class, &req.user, d);
+        let result = if original {
+            let old = old
+                .clone()
+                .map_or(Bson::Null, |d| Bson::Document(protect(d)));
+            types::to_json(doc! {"original": old, "updated": protect(d.clone())})
+        } else {
+            types::to_json(protect(d.clone()))
+        }?;
 
         if let Some(f) = ctx.after_save.get(class) {
             trace!("after save(update): {}", class);
+            req.original = old;
+            req.object = Some(d);
             req = f(req, ctx.clone()).await?;
         };
         Ok(result)
@@ -264,6 +854,7 @@ pub async fn delete(
     ctx: Arc<Context>,
 ) -> Result<impl Reply, Rejection> {
     let class = class.as_str();
+    schema::authorize(&ctx, class, ClassOperation::Delete, &req.user).await?;
     if let Some(f) = ctx.before_destroy.get(class) {
         trace!("before destroy: {}", class);
         req = f(req, ctx.clone()).await?;
@@ -272,10 +863,11 @@ pub async fn delete(
     let mut obj = Object::from_context(ctx.clone(), req.user.clone());
     obj.set_class(class);
     obj.set_id(id);
-    let result = obj.destroy().await.and_then(|d| {
-        serde_json::to_string(&d)
-            .map_or_else(|_e| internal_server_error("Serialization error"), |s| Ok(s))
-    })?;
+    let result = obj
+        .destroy()
+        .await
+        .map(|d| schema::protect(&ctx, class, &req.user, d))
+        .and_then(types::to_json)?;
 
     if let Some(f) = ctx.after_destroy.get(class) {
         trace!("after destroy: {}", class);
@@ -288,11 +880,18 @@ pub async fn delete(
 mod test {
     use std::{collections::HashMap, convert::TryFrom, sync::Arc, thread::sleep, time::Duration};
 
-    use mongodb::bson::Document;
+    use mongodb::bson::{doc, Document};
     use serde_json::{json, Map, Value};
-    use warp::hyper::StatusCode;
+    use warp::{hyper::StatusCode, Rejection};
 
-    use super::super::tests::{test_api, test_server};
+    use super::super::tests::{escape, test_api, test_server, TEST_SERVER_KEY};
+    use crate::{
+        object::ObjectTrait,
+        query::{Filter, Order},
+        server::{Context, Request},
+        types::{GeoPoint, Pointer},
+        with_user, Access, Acl, AclTemplate,
+    };
 
     #[tokio::test]
     async fn test_crud() {
@@ -323,6 +922,14 @@ mod test {
                 .await
         };
 
+        let retrieve1_where = async move |api, class, q| {
+            warp::test::request()
+                .method("GET")
+                .path(&format!("/classes/{}?where={}", class, q))
+                .reply(api)
+                .await
+        };
+
         let update1 = async move |api, class, id, body| {
             warp::test::request()
                 .method("PUT")
@@ -440,8 +1047,24 @@ mod test {
         )
         .unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
-        assert!(body.get_i32("newField").is_err());
-        assert!(body.get("objectId").is_some());
+        assert_eq!(body.get_i32("newField").unwrap(), 1);
+        assert_eq!(body.get_str("objectId").unwrap(), id);
+        assert_eq!(body.get_str("createdAt").unwrap(), created_at);
+        assert!(body.get_str("updatedAt").unwrap() > updated_at);
+
+        // Update with both the original and updated objects returned.
+        let resp = warp::test::request()
+            .method("PUT")
+            .path(&format!("/classes/foo/{}?original=1", id))
+            .json(&json!({"newField": 2}))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body["original"]["newField"], json!(1));
+        assert_eq!(body["updated"]["newField"], json!(2));
+        let resp = update1(&api, "foo", id, json!({"newField": 1})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
 
         // Retrieve the updated object with new updatedAt field.
         let resp = retrieve1(&api, "foo", id).await;
@@ -460,6 +1083,31 @@ mod test {
         let v = body.as_array().unwrap();
         assert_eq!(v.len(), 2);
 
+        // Retrieve by where query, i.e. `{"newField":{"$gte":1}}` and `{"$or":[...]}`.
+        let count = |resp: warp::http::Response<warp::hyper::body::Bytes>| {
+            serde_json::from_slice::<Vec<Value>>(&resp.body()[..])
+                .unwrap()
+                .len()
+        };
+        let resp =
+            retrieve1_where(&api, "foo", "%7B%22newField%22%3A%7B%22%24gte%22%3A1%7D%7D").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(count(resp), 1);
+        let resp =
+            retrieve1_where(&api, "foo", "%7B%22newField%22%3A%7B%22%24lt%22%3A1%7D%7D").await;
+        assert_eq!(count(resp), 0);
+        let resp = retrieve1_where(
+            &api,
+            "foo",
+            "%7B%22%24or%22%3A%5B%7B%22newField%22%3A1%7D%2C%7B%22test-array%22%3A%22b%22%7D%5D%7D",
+        )
+        .await;
+        assert_eq!(count(resp), 2);
+
+        // Operators not in whitelist are rejected.
+        let resp = retrieve1_where(&api, "foo", "%7B%22%24where%22%3A%221%22%7D").await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
         // Test delete non-exist object.
         let resp = delete1(&api, "foo", "xxx").await;
         assert_eq!(resp.status(), StatusCode::NOT_FOUND);
@@ -468,4 +1116,768 @@ mod test {
         let resp = delete1(&api, "foo", id).await;
         assert_eq!(resp.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_query_options() {
+        let api = test_api().await;
+
+        for i in 0..5 {
+            let resp = warp::test::request()
+                .method("POST")
+                .path("/classes/bar")
+                .json(&json!({"i": i, "parity": i % 2, "name": format!("n{}", i)}))
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        }
+
+        let find1 = async move |api, q| {
+            warp::test::request()
+                .method("GET")
+                .path(&format!("/classes/bar?{}", q))
+                .reply(api)
+                .await
+        };
+        let field = |resp: warp::http::Response<warp::hyper::body::Bytes>, k: &str| {
+            serde_json::from_slice::<Vec<Value>>(&resp.body()[..])
+                .unwrap()
+                .into_iter()
+                .map(|v| v.get(k).cloned().unwrap_or(Value::Null))
+                .collect::<Vec<Value>>()
+        };
+
+        let resp = find1(&api, "order=-i&skip=1&limit=2").await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(field(resp, "i"), vec![json!(3), json!(2)]);
+
+        let resp = find1(&api, "order=parity,-i").await;
+        assert_eq!(
+            field(resp, "i"),
+            vec![json!(4), json!(2), json!(0), json!(3), json!(1)]
+        );
+
+        // Only selected fields are returned besides the built-in ones.
+        let resp = find1(&api, "order=i&limit=1&keys=name").await;
+        let v = serde_json::from_slice::<Vec<Value>>(&resp.body()[..]).unwrap();
+        assert_eq!(v.len(), 1);
+        assert_eq!(v[0]["name"], json!("n0"));
+        assert!(v[0].get("objectId").is_some());
+        assert!(v[0].get("i").is_none());
+
+        // Count all matched objects regardless of limit.
+        let resp = find1(&api, "count=1&limit=0").await;
+        let v = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(v, json!({"results": [], "count": 5}));
+        let resp = find1(&api, "count=1&limit=1&where=%7B%22parity%22%3A1%7D").await;
+        let v = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(v["count"], json!(2));
+        assert_eq!(v["results"].as_array().unwrap().len(), 1);
+
+        let resp = find1(&api, "limit=-1").await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = find1(&api, "order=a.$b").await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_update_operations() {
+        let api = test_api().await;
+
+        let request1 = async move |api, method, path, body| {
+            let resp = warp::test::request()
+                .method(method)
+                .path(path)
+                .json(&body)
+                .reply(api)
+                .await;
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            (resp.status(), body)
+        };
+
+        let (status, body) = request1(
+            &api,
+            "POST",
+            "/classes/ops",
+            json!({"n": {"__op": "Increment", "amount": 5}, "tags": ["a"], "x": 1}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["n"], json!(5));
+        let path = format!("/classes/ops/{}", body["objectId"].as_str().unwrap());
+
+        let (status, _) = request1(
+            &api,
+            "PUT",
+            path.as_str(),
+            json!({
+                "n": {"__op": "Increment", "amount": -2},
+                "tags": {"__op": "AddUnique", "objects": ["a", "b"]},
+                "x": {"__op": "Delete"},
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = request1(&api, "GET", path.as_str(), json!({})).await;
+        assert_eq!(body["n"], json!(3));
+        assert_eq!(body["tags"], json!(["a", "b"]));
+        assert!(body.get("x").is_none());
+
+        let (status, _) = request1(
+            &api,
+            "PUT",
+            path.as_str(),
+            json!({
+                "tags": {"__op": "Remove", "objects": ["a"]},
+                "more": {"__op": "Add", "objects": ["c", "c"]},
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, body) = request1(&api, "GET", path.as_str(), json!({})).await;
+        assert_eq!(body["tags"], json!(["b"]));
+        assert_eq!(body["more"], json!(["c", "c"]));
+
+        // Invalid operations.
+        for body in vec![
+            json!({"tags": {"__op": "Increment", "amount": 1}}),
+            json!({"n": {"__op": "Add", "objects": ["d"]}}),
+            json!({"n": {"__op": "Increment", "amount": "1"}}),
+            json!({"n": {"__op": "Multiply", "amount": 2}}),
+            json!({"acl.*": "w"}),
+        ] {
+            let (status, _) = request1(&api, "PUT", path.as_str(), body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+
+        // Overflow of 64-bit integers is rejected, keeping the value stored.
+        let increment = |n: i64| json!({"n": {"__op": "Increment", "amount": n}});
+        let (status, _) = request1(&api, "PUT", path.as_str(), increment(i64::MAX - 3)).await;
+        assert_eq!(status, StatusCode::OK);
+        for n in vec![1, i64::MAX] {
+            let (status, _) = request1(&api, "PUT", path.as_str(), increment(n)).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+        let (_, body) = request1(&api, "GET", path.as_str(), json!({})).await;
+        assert_eq!(body["n"], json!(i64::MAX));
+    }
+
+    /// Reject unless the score of the object saved is one more than that of the original one,
+    /// or one if created.
+    async fn check_original(req: Request, _ctx: Arc<Context>) -> Result<Request, Rejection> {
+        let score = |d: &Option<Document>| {
+            let v = d.as_ref()?.get("score")?;
+            v.as_i32().map(i64::from).or_else(|| v.as_i64())
+        };
+        match (score(&req.original), score(&req.object)) {
+            (None, Some(1)) => Ok(req),
+            (Some(a), Some(b)) if b == a + 1 => Ok(req),
+            _ => super::bad_request("Unexpected objects in after save hook"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_after_save_original() {
+        let mut s = test_server().await;
+        s.after_save("Score", Box::new(|req, ctx| Box::pin(check_original(req, ctx))));
+        let api = s.routes().await;
+        let request1 = async move |api, method, path, body| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .json(&body)
+                .reply(api)
+                .await
+        };
+
+        let resp = request1(&api, "POST", "/classes/Score", json!({"score": 1})).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        let path = format!("/classes/Score/{}", body["objectId"].as_str().unwrap());
+        let increment = json!({"score": {"__op": "Increment", "amount": 1}});
+        let resp = request1(&api, "PUT", path.as_str(), increment).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = request1(&api, "PUT", path.as_str(), json!({"score": 5})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    async fn query_objects(
+        req: Request,
+        ctx: Arc<Context>,
+        arg: HashMap<String, String>,
+    ) -> Result<String, Rejection> {
+        // More than one batch of `Query::each`.
+        for i in 0..150 {
+            let mut obj = ctx.clone().object("baz");
+            obj.set_data(doc! {"i": i, "name": format!("n{}", i)});
+            obj.save().await?;
+        }
+
+        let q = || ctx.clone().query("baz");
+        assert_eq!(q().less_than("i", 10).count().await?, 10);
+
+        let v = q()
+            .contained_in("i", vec![1, 2, 3])
+            .order_by("i", Order::Descending)
+            .skip(1)
+            .find()
+            .await?;
+        let v: Vec<i32> = v.iter().map(|d| d.get_i32("i").unwrap()).collect();
+        assert_eq!(v, vec![2, 1]);
+
+        let d = q()
+            .equal_to("name", "n42")
+            .select(vec!["i"])
+            .first()
+            .await?
+            .unwrap();
+        assert_eq!(d.get_i32("i").unwrap(), 42);
+        assert!(d.get("name").is_none());
+        assert!(q().exists("missing", true).first().await?.is_none());
+
+        // Matches n1, n10 to n19 and n100 to n149.
+        assert_eq!(q().matches("name", "^N1", "i").count().await?, 61);
+        assert!(q().matches("name", "(", "").find().await.is_err());
+
+        let mut n = 0;
+        q().greater_than_or_equal_to("i", 20)
+            .each(|_d| {
+                n += 1;
+                async { Ok::<(), Rejection>(()) }
+            })
+            .await?;
+        assert_eq!(n, 130);
+
+        Ok("ok".to_string())
+    }
+
+    async fn query_pointers(
+        req: Request,
+        ctx: Arc<Context>,
+        arg: HashMap<String, String>,
+    ) -> Result<String, Rejection> {
+        let mut authors = vec![];
+        for name in vec!["alice", "bob"] {
+            let mut obj = ctx.clone().object("Author");
+            obj.set_data(doc! {"name": name});
+            obj.save().await?;
+            authors.push(obj.pointer().unwrap());
+        }
+        let mut carol = ctx.clone().object("Author");
+        carol.set_data(doc! {"name": "carol"});
+        let mut acl = Acl::new();
+        acl.set_public_invisiable();
+        carol.set_acl(acl);
+        carol.save().await?;
+        let mut post = ctx.clone().object("Post");
+        post.set_data(doc! {"title": "world", "author": carol.pointer().unwrap()});
+        post.save().await?;
+
+        let mut post = ctx.clone().object("Post");
+        post.set_data(doc! {"title": "hello", "author": authors[0].clone()});
+        post.add_relation("likes", authors.clone());
+        post.save().await?;
+        let post = post.pointer().unwrap();
+
+        let q = || ctx.clone().query("Post");
+        assert_eq!(q().equal_to("author", authors[0].clone()).count().await?, 1);
+        // Pointers in where are compared regardless of the order of keys.
+        let f = Filter::from_json(&format!(
+            r#"{{"author": {{"objectId": "{}", "className": "Author", "__type": "Pointer"}}}}"#,
+            authors[0].object_id
+        ))?;
+        assert_eq!(q().filter(f).count().await?, 1);
+
+        let name = |n: &str| Filter::from(doc! {"name": n});
+        assert_eq!(
+            q().matches_query("author", "Author", name("alice"))
+                .count()
+                .await?,
+            1
+        );
+        assert_eq!(
+            q().matches_query("author", "Author", name("bob"))
+                .count()
+                .await?,
+            0
+        );
+        let f = Filter::from_json(
+            r#"{"author": {"$inQuery": {"className": "Author", "where": {"name": "alice"}}}}"#,
+        )?;
+        assert_eq!(q().filter(f).count().await?, 1);
+
+        let liked = || {
+            ctx.clone()
+                .query("Author")
+                .related_to(post.clone(), "likes")
+        };
+        assert_eq!(liked().count().await?, 2);
+        let mut obj = ctx.clone().object("Post");
+        obj.set_id(post.object_id.clone());
+        obj.remove_relation("likes", vec![authors[1].clone()]);
+        obj.save().await?;
+        let v = liked().find().await?;
+        assert_eq!(v.len(), 1);
+        assert_eq!(v[0].get_str("name").unwrap(), "alice");
+        let f = Filter::from_json(&format!(
+            r#"{{"$relatedTo": {{"object": {{"__type": "Pointer", "className": "Post", "objectId": "{}"}}, "key": "likes"}}}}"#,
+            post.object_id
+        ))?;
+        assert_eq!(ctx.clone().query("Author").filter(f).count().await?, 1);
+
+        // Pointers to other classes are not allowed in the same relation.
+        let mut obj = ctx.clone().object("Post");
+        obj.set_id(post.object_id.clone());
+        obj.add_relation("likes", vec![Pointer::new("Post", post.object_id.clone())]);
+        assert!(obj.save().await.is_err());
+
+        let d = q()
+            .include(vec!["author"])
+            .order_by("title", Order::Ascending)
+            .first()
+            .await?
+            .unwrap();
+        let author = d.get_document("author").unwrap();
+        assert_eq!(author.get_str("__type").unwrap(), "Object");
+        assert_eq!(author.get_str("className").unwrap(), "Author");
+        assert_eq!(author.get_str("name").unwrap(), "alice");
+
+        Ok("ok".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_client_acl() {
+        let request1 = async move |api, uid, method, path, body| {
+            let resp = with_user!(uid, method)
+                .path(path)
+                .json(&body)
+                .reply(api)
+                .await;
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            (resp.status(), body)
+        };
+        let rw = json!({"read": true, "write": true});
+        let r = json!({"read": true});
+
+        let api = test_api().await;
+        let (status, body) = request1(
+            &api,
+            "a",
+            "POST",
+            "/classes/Post",
+            json!({"title": "x", "ACL": {"a": rw, "*": r}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            body["ACL"]["a"],
+            json!({"read": true, "write": true, "delete": false})
+        );
+        let path = format!("/classes/Post/{}", body["objectId"].as_str().unwrap());
+        let path = path.as_str();
+
+        let (status, _) = request1(&api, "b", "GET", path, json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request1(&api, "b", "PUT", path, json!({"title": "y"})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = request1(&api, "a", "DELETE", path, json!({})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        for acl in vec![
+            json!("w"),
+            json!({"a": "x"}),
+            json!({"a": {"read": 1}}),
+            json!({"a": {"admin": true}}),
+            json!({"a.b": "r"}),
+            json!({"role:": "r"}),
+        ] {
+            let (status, _) = request1(&api, "a", "PUT", path, json!({ "ACL": acl })).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+        }
+
+        // Replaced only by users allowed to delete as well.
+        let body = json!({"ACL": {"a": rw, "b": rw, "*": r}});
+        let (status, _) = request1(&api, "a", "PUT", path, body.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let all = json!({"read": true, "write": true, "delete": true});
+        let post = json!({"title": "x", "ACL": {"a": all, "*": r}});
+        let (status, post) = request1(&api, "a", "POST", "/classes/Post", post).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let path = format!("/classes/Post/{}", post["objectId"].as_str().unwrap());
+        let path = path.as_str();
+        let (status, _) = request1(&api, "a", "PUT", path, body).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request1(&api, "b", "PUT", path, json!({"title": "y"})).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request1(&api, "b", "PUT", path, json!({"ACL": {"b": all}})).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let mut s = test_server().await;
+        s.restrict_acl_grants(true);
+        let api = s.routes().await;
+        let body = json!({"title": "x", "ACL": {"a": r, "*": rw}});
+        let (status, _) = request1(&api, "a", "POST", "/classes/Post", body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let body = json!({"title": "x", "ACL": {"a": r, "b": rw, "*": {}}});
+        let (status, _) = request1(&api, "a", "POST", "/classes/Post", body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // Public is granted nothing if not given.
+        let body = json!({"title": "x", "ACL": {"a": rw, "b": r}});
+        let (status, body) = request1(&api, "a", "POST", "/classes/Post", body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            body["ACL"]["*"],
+            json!({"read": false, "write": false, "delete": false})
+        );
+        let write = json!({"write": true, "delete": true});
+        let body = json!({"title": "x", "ACL": {"a": write, "b": {}, "*": {}}});
+        let (status, body) = request1(&api, "a", "POST", "/classes/Post", body).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let path = format!("/classes/Post/{}", body["objectId"].as_str().unwrap());
+        let path = path.as_str();
+
+        let body = json!({"ACL": {"a": write, "b": rw, "*": {}}});
+        let (status, _) = request1(&api, "a", "PUT", path, body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let body = json!({"ACL": {"a": write, "b": {"write": true}, "*": {}}});
+        let (status, _) = request1(&api, "a", "PUT", path, body).await;
+        assert_eq!(status, StatusCode::OK);
+        // Not writable by the caller at all.
+        let body = json!({"ACL": {"c": rw}});
+        let (status, _) = request1(&api, "c", "PUT", path, body).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    async fn share_memo(mut req: Request, _ctx: Arc<Context>) -> Result<Request, Rejection> {
+        if let Some(acl) = req.acl.as_mut() {
+            acl.set_readonly("b");
+        }
+        Ok(req)
+    }
+
+    #[tokio::test]
+    async fn test_default_acl() {
+        let mut s = test_server().await;
+        let mut acl = Acl::new();
+        acl.set_public_readonly();
+        let rw = Access {
+            delete: false,
+            ..Access::ALL
+        };
+        s.set_default_acl("Note", AclTemplate::new(acl).creator(rw));
+        s.set_default_acl("Memo", AclTemplate::owner_only());
+        s.before_save("Memo", Box::new(|req, ctx| Box::pin(share_memo(req, ctx))));
+        let api = s.routes().await;
+
+        let request1 = async move |api, uid, method, path, body| {
+            let resp = with_user!(uid, method)
+                .path(path)
+                .json(&body)
+                .reply(api)
+                .await;
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            (resp.status(), body)
+        };
+        let create1 = async move |api, class, body| {
+            let resp = with_user!("a", "POST")
+                .path(&format!("/classes/{}", class))
+                .json(&body)
+                .reply(api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            format!("/classes/{}/{}", class, body["objectId"].as_str().unwrap())
+        };
+
+        // Creator read-write and public read-only.
+        let path = create1(&api, "Note", json!({"title": "x"})).await;
+        let path = path.as_str();
+        let (status, body) = request1(&api, "a", "PUT", path, json!({"title": "y"})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["ACL"]["a"],
+            json!({"read": true, "write": true, "delete": false})
+        );
+        assert_eq!(
+            body["ACL"]["*"],
+            json!({"read": true, "write": false, "delete": false})
+        );
+        let (status, _) = request1(&api, "b", "GET", path, json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request1(&api, "b", "PUT", path, json!({"title": "z"})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Owner only, while shared with `b` by the hook.
+        let path = create1(&api, "Memo", json!({"title": "x"})).await;
+        let path = path.as_str();
+        let (status, _) = request1(&api, "a", "DELETE", path, json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        let path = create1(&api, "Memo", json!({"title": "x"})).await;
+        let path = path.as_str();
+        let (status, _) = request1(&api, "b", "GET", path, json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request1(&api, "c", "GET", path, json!({})).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // ACL given by the client takes precedence, and is overridden by the hook as well.
+        let body = json!({"title": "x", "ACL": {"a": "w", "*": "r"}});
+        let path = create1(&api, "Memo", body).await;
+        let (status, body) = request1(&api, "c", "GET", path.as_str(), json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["ACL"]["b"],
+            json!({"read": true, "write": false, "delete": false})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pointers() {
+        let mut s = test_server().await;
+        s.define(
+            "pointers",
+            Box::new(|req, ctx, arg| Box::pin(query_pointers(req, ctx, arg))),
+        );
+        let api = s.routes().await;
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/functions/pointers")
+            .reply(&api)
+            .await;
+        assert_eq!(String::from_utf8(resp.body()[..].to_vec()).unwrap(), "ok");
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let request1 = async move |api, method, path: String, body| {
+            let resp = warp::test::request()
+                .method(method)
+                .path(&path)
+                .header("x-parse-master-key", TEST_SERVER_KEY)
+                .json(&body)
+                .reply(api)
+                .await;
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            (resp.status(), body)
+        };
+
+        let (status, body) = request1(
+            &api,
+            "GET",
+            "/classes/Post?include=author&order=title".to_string(),
+            json!({}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["author"]["name"], json!("alice"));
+        assert_eq!(body[1]["author"]["name"], json!("carol"));
+        let path = format!(
+            "/classes/Post/{}?include=author",
+            body[0]["objectId"].as_str().unwrap()
+        );
+        let (status, body) = request1(&api, "GET", path, json!({})).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["author"]["name"], json!("alice"));
+
+        // Objects invisible to the reader are left as pointers.
+        let resp = warp::test::request()
+            .path("/classes/Post?include=author&order=title")
+            .reply(&api)
+            .await;
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body[0]["author"]["name"], json!("alice"));
+        assert_eq!(body[1]["author"]["__type"], json!("Pointer"));
+        assert!(body[1]["author"].get("name").is_none());
+
+        // Invalid pointers are rejected.
+        let (status, _) = request1(
+            &api,
+            "POST",
+            "/classes/Post".to_string(),
+            json!({"author": {"__type": "Pointer", "className": "Author"}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    async fn query_geo(
+        req: Request,
+        ctx: Arc<Context>,
+        arg: HashMap<String, String>,
+    ) -> Result<String, Rejection> {
+        let p = |lat, lng| GeoPoint::new(lat, lng).unwrap();
+        let stores = vec![
+            ("a", p(30.0, 120.0)),
+            ("b", p(30.1, 120.0)),
+            ("c", p(31.0, 120.0)),
+            ("d", p(40.0, 116.0)),
+        ];
+        for (name, loc) in stores {
+            let mut obj = ctx.clone().object("Store");
+            obj.set_data(doc! {"name": name, "loc": loc});
+            obj.save().await?;
+        }
+
+        let q = || ctx.clone().query("Store");
+        let names = |v: Vec<Document>| {
+            v.iter()
+                .map(|d| d.get_str("name").unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+        let v = q().near("loc", p(30.08, 120.0)).find().await?;
+        assert_eq!(names(v), vec!["b", "a", "c", "d"]);
+        let v = q()
+            .within_kilometers("loc", p(30.0, 120.0), 20.0)
+            .find()
+            .await?;
+        assert_eq!(names(v), vec!["a", "b"]);
+        let v = q()
+            .within_kilometers("loc", p(30.0, 120.0), 200.0)
+            .order_by("name", Order::Descending)
+            .find()
+            .await?;
+        assert_eq!(names(v), vec!["c", "b", "a"]);
+        let n = q()
+            .within_kilometers("loc", p(30.0, 120.0), 200.0)
+            .count()
+            .await?;
+        assert_eq!(n, 3);
+        let n = q()
+            .within_geo_box("loc", p(29.0, 119.0), p(30.5, 121.0))
+            .count()
+            .await?;
+        assert_eq!(n, 2);
+        let triangle = vec![p(29.0, 119.0), p(29.0, 121.0), p(32.0, 120.0)];
+        let n = q().within_polygon("loc", triangle).count().await?;
+        assert_eq!(n, 3);
+
+        Ok("ok".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_geo() {
+        let mut s = test_server().await;
+        s.define(
+            "geo",
+            Box::new(|req, ctx, arg| Box::pin(query_geo(req, ctx, arg))),
+        );
+        let api = s.routes().await;
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/functions/geo")
+            .reply(&api)
+            .await;
+        assert_eq!(String::from_utf8(resp.body()[..].to_vec()).unwrap(), "ok");
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let point = |lat, lng| json!({"__type": "GeoPoint", "latitude": lat, "longitude": lng});
+        let find1 = async move |api, w: Value| {
+            let resp = warp::test::request()
+                .path(&format!("/classes/Store?where={}", escape(w)))
+                .reply(api)
+                .await;
+            let v = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            (resp.status(), v)
+        };
+        let (status, v) = find1(
+            &api,
+            json!({"loc": {"$nearSphere": point(30.08, 120.0), "$maxDistanceInKilometers": 20}}),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(v[0]["name"], json!("b"));
+        assert_eq!(v[0]["loc"], point(30.1, 120.0));
+        assert_eq!(v[1]["name"], json!("a"));
+        assert_eq!(v.as_array().unwrap().len(), 2);
+
+        let (_, v) = find1(
+            &api,
+            json!({"loc": {"$within": {"$box": [point(35.0, 110.0), point(45.0, 120.0)]}}}),
+        )
+        .await;
+        assert_eq!(v.as_array().unwrap().len(), 1);
+        assert_eq!(v[0]["name"], json!("d"));
+
+        let polygon = json!([point(29.0, 119.0), point(29.0, 121.0), point(30.05, 120.0)]);
+        let (_, v) = find1(&api, json!({"loc": {"$geoWithin": {"$polygon": polygon}}})).await;
+        assert_eq!(v.as_array().unwrap().len(), 1);
+        assert_eq!(v[0]["name"], json!("a"));
+
+        let (status, _) = find1(&api, json!({"loc": {"$nearSphere": [120, 30]}})).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_typed_fields() {
+        let api = test_api().await;
+        let date =
+            |d: u32| json!({"__type": "Date", "iso": format!("2020-12-0{}T08:00:00.000Z", d)});
+        let bytes = json!({"__type": "Bytes", "base64": "aGVsbG8="});
+        let point = json!({"__type": "GeoPoint", "latitude": 30.5, "longitude": 120.0});
+        let file = json!({"__type": "File", "name": "a.txt", "url": "http://localhost/a.txt"});
+        for d in 1..4 {
+            let resp = warp::test::request()
+                .method("POST")
+                .path("/classes/event")
+                .json(&json!({"when": date(d), "data": bytes, "at": point, "file": file}))
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            assert_eq!(body["when"], date(d));
+        }
+
+        let q = escape(json!({"when": {"$gte": date(2)}}));
+        let resp = warp::test::request()
+            .path(&format!("/classes/event?order=-when&where={}", q))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = serde_json::from_slice::<Vec<Value>>(&resp.body()[..]).unwrap();
+        assert_eq!(body.len(), 2);
+        assert_eq!(body[0]["when"], date(3));
+        assert_eq!(body[1]["when"], date(2));
+        assert_eq!(body[0]["data"], bytes);
+        assert_eq!(body[0]["at"], point);
+        assert_eq!(body[0]["file"], file);
+
+        let q = escape(json!({"when": {"$lt": date(2)}}));
+        let resp = warp::test::request()
+            .path(&format!("/classes/event?count=1&limit=0&where={}", q))
+            .reply(&api)
+            .await;
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body["count"], json!(1));
+
+        // Values of special types are validated.
+        for v in vec![
+            json!({"__type": "Date", "iso": "yesterday"}),
+            json!({"__type": "GeoPoint", "latitude": 100, "longitude": 0}),
+            json!({"__type": "Unknown"}),
+        ] {
+            let resp = warp::test::request()
+                .method("POST")
+                .path("/classes/event")
+                .json(&json!({ "v": v }))
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let mut s = test_server().await;
+        s.define(
+            "query",
+            Box::new(|req, ctx, arg| Box::pin(query_objects(req, ctx, arg))),
+        );
+        let api = s.routes().await;
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/functions/query")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(String::from_utf8(resp.body()[..].to_vec()).unwrap(), "ok");
+    }
 }
@@ -0,0 +1,620 @@
+use std::collections::HashMap;
+
+use mongodb::bson::{Bson, Document};
+use regex::Regex;
+use serde_json::{Map, Value};
+use warp::Rejection;
+
+use crate::{
+    error::bad_request,
+    types::{self, GeoPoint, Pointer},
+};
+
+/// Constraint on value of a field.
+///
+/// If the field is an array, the constraint holds when any element of it satisfies,
+/// except that `NotEqual` and `NotIn` require all elements to satisfy.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    /// `$eq`, equal to the value. Null value also matches missing field.
+    Equal(Bson),
+    /// `$ne`, not equal to the value.
+    NotEqual(Bson),
+    /// `$lt`, less than the value.
+    LessThan(Bson),
+    /// `$lte`, less than or equal to the value.
+    LessThanOrEqual(Bson),
+    /// `$gt`, greater than the value.
+    GreaterThan(Bson),
+    /// `$gte`, greater than or equal to the value.
+    GreaterThanOrEqual(Bson),
+    /// `$in`, equal to any of the values.
+    In(Vec<Bson>),
+    /// `$nin`, equal to none of the values.
+    NotIn(Vec<Bson>),
+    /// `$exists`, whether the field exists.
+    Exists(bool),
+    /// `$regex` with `$options`, matching the regular expression pattern with options
+    /// consisting of `i`, `m`, `s` and `x`.
+    Regex(String, String),
+    /// `$inQuery` of `{"className": CLASS, "where": ...}`, pointing to any object in the class
+    /// matching the filter.
+    ///
+    /// Subqueries are resolved into other constraints before translated by each database.
+    InQuery(String, Box<Filter>),
+    /// `$nearSphere` with optional `$maxDistanceInKilometers`, a geo point within the distance
+    /// of the point if any.
+    ///
+    /// Objects are sorted from the nearest to the farthest unless sorted by other fields.
+    NearSphere(GeoPoint, Option<f64>),
+    /// `$within` of `{"$box": [SOUTHWEST, NORTHEAST]}`, a geo point within the box of corners.
+    WithinBox(GeoPoint, GeoPoint),
+    /// `$geoWithin` of `{"$polygon": [POINT, ...]}`, a geo point within the polygon of at least
+    /// three vertices.
+    WithinPolygon(Vec<GeoPoint>),
+}
+
+/// Conditions on objects, translated by each database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// `$and`, all of the filters hold, which is always true if empty.
+    And(Vec<Filter>),
+    /// `$or`, any of the filters holds, which is always false if empty.
+    Or(Vec<Filter>),
+    /// Constraint on field of dot-separated path such as `a.b`.
+    Field(String, Constraint),
+    /// `$relatedTo` of `{"object": POINTER, "key": KEY}`, in relation `KEY` of the object
+    /// referenced by the pointer.
+    ///
+    /// Subqueries are resolved into other constraints before translated by each database.
+    RelatedTo(Pointer, String),
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::And(vec![])
+    }
+}
+
+/// Filter of equality on every field of the document.
+impl From<Document> for Filter {
+    fn from(doc: Document) -> Self {
+        Filter::And(
+            doc.into_iter()
+                .map(|(k, v)| Filter::Field(k, Constraint::Equal(v)))
+                .collect(),
+        )
+    }
+}
+
+/// Sort order of a field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// From the smallest to the largest.
+    Ascending,
+    /// From the largest to the smallest.
+    Descending,
+}
+
+/// Query on objects of a class, i.e. filter together with sorting, pagination and projection.
+///
+/// Built by chaining, e.g. `Query::from(filter).order_by("score", Order::Descending).limit(10)`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    /// Conditions that objects returned should satisfy.
+    pub filter: Filter,
+    /// Fields to sort by in order of priority, while the order of objects is unspecified if empty.
+    pub order: Vec<(String, Order)>,
+    /// Number of objects to skip.
+    pub skip: u64,
+    /// Maximum number of objects to return, unlimited if `None`.
+    pub limit: Option<u64>,
+    /// Fields to return besides `objectId`, `createdAt`, `updatedAt` and `acl`, all if `None`.
+    pub keys: Option<Vec<String>>,
+}
+
+impl From<Filter> for Query {
+    fn from(filter: Filter) -> Self {
+        Self {
+            filter,
+            ..Self::default()
+        }
+    }
+}
+
+/// Query of equality on every field of the document.
+impl From<Document> for Query {
+    fn from(doc: Document) -> Self {
+        Filter::from(doc).into()
+    }
+}
+
+/// Regular expression options that are allowed and supported by all databases.
+const REGEX_OPTIONS: &'static str = "imsx";
+
+/// Build regular expression by pattern and options validated by `Filter::from_json`.
+pub(crate) fn regex(pattern: &str, options: &str) -> Option<Regex> {
+    if options.is_empty() {
+        Regex::new(pattern).ok()
+    } else {
+        Regex::new(&format!("(?{}){}", options, pattern)).ok()
+    }
+}
+
+fn field_name(key: &str) -> Result<(), Rejection> {
+    if key.is_empty() || key.split('.').any(|k| k.is_empty() || k.starts_with('$')) {
+        bad_request(format!("Invalid field name {}", key))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse comma-separated field names such as `keys` and `include` of query string.
+pub(crate) fn fields(v: &str) -> Result<Vec<String>, Rejection> {
+    v.split(',')
+        .map(|k| field_name(k).map(|_| k.to_string()))
+        .collect()
+}
+
+/// Convert JSON value into BSON, where keys starting with `$` are rejected to avoid operator injection
+/// and values of special types such as pointers are validated.
+fn value(v: Value) -> Result<Bson, Rejection> {
+    Ok(match v {
+        Value::Null => Bson::Null,
+        Value::Bool(b) => Bson::Boolean(b),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                if i >= i32::MIN as i64 && i <= i32::MAX as i64 {
+                    Bson::Int32(i as i32)
+                } else {
+                    Bson::Int64(i)
+                }
+            } else {
+                Bson::Double(n.as_f64().unwrap_or_default())
+            }
+        }
+        Value::String(s) => Bson::String(s),
+        Value::Array(a) => Bson::Array(a.into_iter().map(value).collect::<Result<_, _>>()?),
+        Value::Object(m) => {
+            let mut doc = Document::new();
+            for (k, v) in m {
+                field_name(&k)?;
+                doc.insert(k, value(v)?);
+            }
+            types::decode(Bson::Document(doc))?
+        }
+    })
+}
+
+fn point(v: Value) -> Result<GeoPoint, Rejection> {
+    match GeoPoint::from_bson(&value(v)?) {
+        Some(p) => Ok(p),
+        None => bad_request("Expect a GeoPoint"),
+    }
+}
+
+/// Geo points of `v`, which is `{KEY: [POINT, ...]}` of `$within` and `$geoWithin`.
+fn points(v: &Value, key: &str) -> Result<Vec<GeoPoint>, Rejection> {
+    match v.get(key) {
+        Some(Value::Array(a)) => a.iter().cloned().map(point).collect(),
+        _ => bad_request(format!("Expect an array of GeoPoint in {}", key)),
+    }
+}
+
+/// Units of `$maxDistance*` in kilometers.
+const DISTANCES: &[(&'static str, f64)] = &[
+    ("$maxDistance", types::EARTH_RADIUS),
+    ("$maxDistanceInRadians", types::EARTH_RADIUS),
+    ("$maxDistanceInKilometers", 1.0),
+    ("$maxDistanceInMiles", 1.609344),
+];
+
+fn values(v: Value) -> Result<Vec<Bson>, Rejection> {
+    match v {
+        Value::Array(a) => a.into_iter().map(value).collect(),
+        _ => bad_request("Expect an array of values"),
+    }
+}
+
+impl Filter {
+    /// Parse a Parse-style `where` JSON such as `{"score": {"$gte": 10}, "name": "foo"}`.
+    ///
+    /// Only operators of `Constraint` together with `$or` and `$and` are allowed.
+    pub fn from_json(s: &str) -> Result<Self, Rejection> {
+        let f = match serde_json::from_str::<Value>(s) {
+            Ok(Value::Object(m)) => Self::from_map(m)?,
+            _ => return bad_request("Expect a JSON object of where"),
+        };
+        f.check_near(true)?;
+        Ok(f)
+    }
+
+    /// Check that `$nearSphere` is only under `$and` from the root of the filter or of its
+    /// subqueries, where objects can be sorted by distance, i.e. not under `$or`.
+    pub(crate) fn check_near(&self, top: bool) -> Result<(), Rejection> {
+        match self {
+            Filter::And(v) => v.iter().try_for_each(|f| f.check_near(top)),
+            Filter::Or(v) => v.iter().try_for_each(|f| f.check_near(false)),
+            Filter::Field(_, Constraint::NearSphere(..)) if !top => {
+                bad_request("$nearSphere is not allowed under $or")
+            }
+            Filter::Field(_, Constraint::InQuery(_, f)) => f.check_near(true),
+            _ => Ok(()),
+        }
+    }
+
+    fn from_map(m: Map<String, Value>) -> Result<Self, Rejection> {
+        let mut filters = vec![];
+        for (k, v) in m {
+            match k.as_str() {
+                "$or" | "$and" => {
+                    let mut subs = vec![];
+                    for sub in values_of(v)? {
+                        match sub {
+                            Value::Object(m) => subs.push(Self::from_map(m)?),
+                            _ => return bad_request(format!("Expect objects in {}", k)),
+                        }
+                    }
+                    filters.push(if k == "$or" {
+                        Filter::Or(subs)
+                    } else {
+                        Filter::And(subs)
+                    });
+                }
+                "$relatedTo" => {
+                    let (p, key) = match v {
+                        Value::Object(mut m) => (m.remove("object"), m.remove("key")),
+                        _ => (None, None),
+                    };
+                    let p = p.map(value).transpose()?;
+                    match (p.as_ref().and_then(Pointer::from_bson), key) {
+                        (Some(p), Some(Value::String(key))) => {
+                            field_name(&key)?;
+                            filters.push(Filter::RelatedTo(p, key));
+                        }
+                        _ => {
+                            return bad_request("Expect a pointer of object and key of $relatedTo")
+                        }
+                    }
+                }
+                _ => {
+                    field_name(&k)?;
+                    filters.extend(Self::from_field(k, v)?);
+                }
+            }
+        }
+        Ok(Filter::And(filters))
+    }
+
+    fn from_field(key: String, v: Value) -> Result<Vec<Self>, Rejection> {
+        let m = match v {
+            Value::Object(m) if m.keys().any(|k| k.starts_with('$')) => m,
+            v => return Ok(vec![Filter::Field(key, Constraint::Equal(value(v)?))]),
+        };
+
+        let options = match m.get("$options") {
+            None => "",
+            Some(Value::String(o)) if o.chars().all(|c| REGEX_OPTIONS.contains(c)) => o.as_str(),
+            Some(_) => return bad_request("Invalid $options"),
+        };
+        let mut distance = None;
+        for (k, unit) in DISTANCES.iter() {
+            match m.get(*k) {
+                None => {}
+                Some(Value::Number(n)) if n.as_f64().map_or(false, |d| d >= 0.0) => {
+                    distance = n.as_f64().map(|d| d * unit);
+                }
+                Some(_) => return bad_request(format!("Expect a non-negative number of {}", k)),
+            }
+        }
+        let mut filters = vec![];
+        for (op, v) in m.iter() {
+            let c = match op.as_str() {
+                "$eq" => Constraint::Equal(value(v.clone())?),
+                "$ne" => Constraint::NotEqual(value(v.clone())?),
+                "$lt" => Constraint::LessThan(value(v.clone())?),
+                "$lte" => Constraint::LessThanOrEqual(value(v.clone())?),
+                "$gt" => Constraint::GreaterThan(value(v.clone())?),
+                "$gte" => Constraint::GreaterThanOrEqual(value(v.clone())?),
+                "$in" => Constraint::In(values(v.clone())?),
+                "$nin" => Constraint::NotIn(values(v.clone())?),
+                "$exists" => match v {
+                    Value::Bool(b) => Constraint::Exists(*b),
+                    _ => return bad_request("Expect a boolean of $exists"),
+                },
+                "$regex" => match v {
+                    Value::String(p) if regex(p, options).is_some() => {
+                        Constraint::Regex(p.clone(), options.to_string())
+                    }
+                    _ => return bad_request("Invalid $regex"),
+                },
+                "$inQuery" => match v {
+                    Value::Object(q) => match (q.get("className"), q.get("where")) {
+                        (Some(Value::String(c)), Some(Value::Object(w)))
+                            if types::class_name(c) =>
+                        {
+                            Constraint::InQuery(c.clone(), Box::new(Self::from_map(w.clone())?))
+                        }
+                        _ => return bad_request("Expect className and where of $inQuery"),
+                    },
+                    _ => return bad_request("Expect an object of $inQuery"),
+                },
+                "$nearSphere" => Constraint::NearSphere(point(v.clone())?, distance),
+                "$within" => match points(v, "$box")?.as_slice() {
+                    [sw, ne] if sw.latitude <= ne.latitude && sw.longitude <= ne.longitude => {
+                        Constraint::WithinBox(*sw, *ne)
+                    }
+                    _ => return bad_request("Expect southwest and northeast corners of $box"),
+                },
+                "$geoWithin" => match points(v, "$polygon")? {
+                    p if p.len() >= 3 => Constraint::WithinPolygon(p),
+                    _ => return bad_request("Expect at least three points of $polygon"),
+                },
+                "$options" if m.contains_key("$regex") => continue,
+                k if m.contains_key("$nearSphere") && DISTANCES.iter().any(|(d, _)| *d == k) => {
+                    continue
+                }
+                _ => return bad_request(format!("Operator {} not allowed", op)),
+            };
+            filters.push(Filter::Field(key.clone(), c));
+        }
+        Ok(filters)
+    }
+
+    /// Parse query string of `GET /classes/{class}`, where `where` is parsed by `from_json`
+    /// and other parameters are taken as equality on string fields.
+    pub fn from_query(mut q: HashMap<String, String>) -> Result<Self, Rejection> {
+        let mut filters = vec![];
+        if let Some(w) = q.remove("where") {
+            filters.push(Self::from_json(&w)?);
+        }
+        for (k, v) in q {
+            field_name(&k)?;
+            filters.push(Filter::Field(k, Constraint::Equal(Bson::String(v))));
+        }
+        Ok(Filter::And(filters))
+    }
+}
+
+impl Query {
+    /// Set the filter of objects.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Sort by `key` after the fields already specified.
+    pub fn order_by(mut self, key: impl Into<String>, order: Order) -> Self {
+        self.order.push((key.into(), order));
+        self
+    }
+
+    /// Skip the first `n` objects.
+    pub fn skip(mut self, n: u64) -> Self {
+        self.skip = n;
+        self
+    }
+
+    /// Return at most `n` objects.
+    pub fn limit(mut self, n: u64) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Only return fields of `keys`, as well as `objectId`, `createdAt`, `updatedAt` and `acl`.
+    pub fn select<T: Into<String>>(mut self, keys: impl IntoIterator<Item = T>) -> Self {
+        self.keys = Some(keys.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Parse query string of `GET /classes/{class}`, consisting of `limit`, `skip`,
+    /// `order` of comma-separated fields where descending ones are prefixed with `-`,
+    /// `keys` of comma-separated fields and the filter parsed by `Filter::from_query`.
+    pub fn from_query(mut q: HashMap<String, String>) -> Result<Self, Rejection> {
+        // Databases take them as signed 64-bit integers.
+        let number = |k: &str, v: Option<String>| match v.map(|v| v.parse::<i64>()) {
+            None => Ok(None),
+            Some(Ok(n)) if n >= 0 => Ok(Some(n as u64)),
+            Some(_) => bad_request(format!("Expect a non-negative 64-bit integer of {}", k)),
+        };
+        let limit = number("limit", q.remove("limit"))?;
+        let skip = number("skip", q.remove("skip"))?.unwrap_or_default();
+        let order = match q.remove("order") {
+            Some(v) => v
+                .split(',')
+                .map(|k| {
+                    let (k, order) = match k.strip_prefix('-') {
+                        Some(k) => (k, Order::Descending),
+                        None => (k, Order::Ascending),
+                    };
+                    field_name(k).map(|_| (k.to_string(), order))
+                })
+                .collect::<Result<_, _>>()?,
+            None => vec![],
+        };
+        let keys = q.remove("keys").map(|v| fields(&v)).transpose()?;
+        Ok(Self {
+            filter: Filter::from_query(q)?,
+            order,
+            skip,
+            limit,
+            keys,
+        })
+    }
+}
+
+/// Boolean parameter `key` of query string, which is false if missing.
+pub(crate) fn flag(q: &HashMap<String, String>, key: &str) -> Result<bool, Rejection> {
+    match q.get(key).map(String::as_str) {
+        None | Some("0") => Ok(false),
+        Some("1") => Ok(true),
+        Some(_) => bad_request(format!("Expect 0 or 1 of {}", key)),
+    }
+}
+
+fn values_of(v: Value) -> Result<Vec<Value>, Rejection> {
+    match v {
+        Value::Array(a) => Ok(a),
+        _ => bad_request("Expect an array of $or or $and"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_filter_from_json() {
+        let f = Filter::from_json(r#"{"a": {"$gte": 1, "$lt": 5}, "b": "x"}"#).unwrap();
+        match f {
+            Filter::And(v) => {
+                assert_eq!(v.len(), 3);
+                assert!(v.contains(&Filter::Field(
+                    "a".to_string(),
+                    Constraint::GreaterThanOrEqual(Bson::Int32(1))
+                )));
+                assert!(v.contains(&Filter::Field(
+                    "b".to_string(),
+                    Constraint::Equal(Bson::String("x".to_string()))
+                )));
+            }
+            _ => panic!("unexpected filter {:?}", f),
+        }
+
+        let f = Filter::from_json(r#"{"$or": [{"a": 1}, {"b": {"$in": [1, 2]}}]}"#).unwrap();
+        assert_eq!(
+            f,
+            Filter::And(vec![Filter::Or(vec![
+                Filter::And(vec![Filter::Field(
+                    "a".to_string(),
+                    Constraint::Equal(Bson::Int32(1))
+                )]),
+                Filter::And(vec![Filter::Field(
+                    "b".to_string(),
+                    Constraint::In(vec![Bson::Int32(1), Bson::Int32(2)])
+                )]),
+            ])])
+        );
+
+        let f = Filter::from_json(r#"{"a": {"x": 1}}"#).unwrap();
+        assert_eq!(
+            f,
+            Filter::And(vec![Filter::Field(
+                "a".to_string(),
+                Constraint::Equal(Bson::Document(doc! {"x": 1}))
+            )])
+        );
+    }
+
+    #[test]
+    fn test_filter_whitelist() {
+        assert!(Filter::from_json(r#"{"$where": "sleep(1000)"}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$where": "1"}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"x": {"$gt": 1}}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a.$b": 1}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$in": 1}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$exists": 1}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$options": "i"}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$regex": "x", "$options": "g"}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$regex": "(", "$options": "i"}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$regex": "^x", "$options": "i"}}"#).is_ok());
+        assert!(Filter::from_json(r#"[1]"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$inQuery": {"className": "b"}}}"#).is_err());
+        assert!(
+            Filter::from_json(r#"{"a": {"$inQuery": {"className": "b.c", "where": {}}}}"#).is_err()
+        );
+        assert!(Filter::from_json(r#"{"$relatedTo": {"key": "a"}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"__type": "Pointer", "className": "b"}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$maxDistanceInKilometers": 1}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$nearSphere": [0, 0]}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$within": {"$box": []}}}"#).is_err());
+        assert!(Filter::from_json(r#"{"a": {"$geoWithin": {"$centerSphere": []}}}"#).is_err());
+    }
+
+    #[test]
+    fn test_filter_geo() {
+        let p = |lat, lng| GeoPoint::new(lat, lng).unwrap();
+        let point = |lat, lng| json!({"__type": "GeoPoint", "latitude": lat, "longitude": lng});
+        let f = Filter::from_json(
+            &json!({"a": {"$nearSphere": point(1, 2), "$maxDistanceInKilometers": 10}}).to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            f,
+            Filter::And(vec![Filter::Field(
+                "a".to_string(),
+                Constraint::NearSphere(p(1.0, 2.0), Some(10.0))
+            )])
+        );
+        let f = Filter::from_json(
+            &json!({"a": {"$within": {"$box": [point(0, 0), point(1, 1)]}}}).to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            f,
+            Filter::And(vec![Filter::Field(
+                "a".to_string(),
+                Constraint::WithinBox(p(0.0, 0.0), p(1.0, 1.0))
+            )])
+        );
+        let polygon = json!({"$polygon": [point(0, 0), point(0, 1), point(1, 0)]});
+        let f = Filter::from_json(&json!({"a": {"$geoWithin": polygon}}).to_string()).unwrap();
+        assert_eq!(
+            f,
+            Filter::And(vec![Filter::Field(
+                "a".to_string(),
+                Constraint::WithinPolygon(vec![p(0.0, 0.0), p(0.0, 1.0), p(1.0, 0.0)])
+            )])
+        );
+
+        let box1 = json!({"a": {"$within": {"$box": [point(1, 1), point(0, 0)]}}});
+        assert!(Filter::from_json(&box1.to_string()).is_err());
+        let polygon = json!({"a": {"$geoWithin": {"$polygon": [point(0, 0), point(0, 1)]}}});
+        assert!(Filter::from_json(&polygon.to_string()).is_err());
+        let near = json!({"a": {"$nearSphere": point(0, 0), "$maxDistanceInKilometers": -1}});
+        assert!(Filter::from_json(&near.to_string()).is_err());
+        let near = json!({"$and": [{"a": {"$nearSphere": point(0, 0)}}]});
+        assert!(Filter::from_json(&near.to_string()).is_ok());
+        let near = json!({"$or": [{"a": {"$nearSphere": point(0, 0)}}, {"b": 1}]});
+        assert!(Filter::from_json(&near.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_query_from_query() {
+        let q: HashMap<String, String> = vec![
+            ("where", r#"{"a": 1}"#),
+            ("order", "-score,name"),
+            ("skip", "20"),
+            ("limit", "10"),
+            ("keys", "name,score"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(
+            Query::from_query(q).unwrap(),
+            Query::from(Filter::And(vec![Filter::from_json(r#"{"a": 1}"#).unwrap()]))
+                .order_by("score", Order::Descending)
+                .order_by("name", Order::Ascending)
+                .skip(20)
+                .limit(10)
+                .select(vec!["name", "score"])
+        );
+
+        let q = |k: &str, v: &str| {
+            let mut q = HashMap::new();
+            q.insert(k.to_string(), v.to_string());
+            Query::from_query(q)
+        };
+        assert!(q("limit", "-1").is_err());
+        assert!(q("skip", "x").is_err());
+        assert!(q("skip", "9223372036854775808").is_err());
+        assert!(q("limit", "9223372036854775807").is_ok());
+        assert!(q("order", "a,,b").is_err());
+        assert!(q("keys", "$a").is_err());
+        assert_eq!(q("limit", "0").unwrap().limit, Some(0));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashSet;
+
+use mongodb::bson::Bson;
+use warp::Rejection;
+
+use crate::{
+    database::{Database, OBJECT_ID},
+    query::{Constraint, Filter, Query},
+    user::UserKind,
+};
+
+/// Class of roles, each with a unique `name`, relation `users` to its users and relation
+/// `roles` to its child roles, whose users are granted whatever granted to the role.
+pub const ROLE: &'static str = "_Role";
+
+/// Prefix of keys in ACL for roles, e.g. `role:Admin`.
+pub const PREFIX: &'static str = "role:";
+
+/// Key of role `name` in ACL.
+pub(crate) fn key(name: &str) -> String {
+    format!("{}{}", PREFIX, name)
+}
+
+/// Check if `name` is a valid name of role, which is used as key of ACL.
+pub(crate) fn role_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == ' ')
+}
+
+/// Names of roles of user `id`, including those of parent roles transitively.
+pub(crate) async fn resolve(db: &dyn Database, id: &str) -> Result<Vec<String>, Rejection> {
+    let mut names = vec![];
+    let mut seen = HashSet::new();
+    let mut filter = Filter::Field("users.objectIds".to_string(), Constraint::Equal(id.into()));
+    loop {
+        let query = Query::from(filter).select(vec![OBJECT_ID, "name"]);
+        let mut found = vec![];
+        for d in db.retrieve(ROLE, query, UserKind::Master).await? {
+            let id = d.get_str(OBJECT_ID).unwrap_or_default();
+            if !seen.insert(id.to_string()) {
+                continue;
+            }
+            found.push(Bson::String(id.to_string()));
+            match d.get_str("name") {
+                Ok(n) if role_name(n) => names.push(n.to_string()),
+                _ => warn!("role {} without a valid name ignored", id),
+            }
+        }
+        if found.is_empty() {
+            break;
+        }
+        filter = Filter::Field("roles.objectIds".to_string(), Constraint::In(found));
+    }
+    trace!("roles of user {}: {:?}", id, names);
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use mongodb::bson::doc;
+    use serde_json::json;
+    use warp::{hyper::StatusCode, Rejection};
+
+    use super::{super::tests::test_server, resolve, ROLE};
+    use crate::{
+        object::ObjectTrait,
+        server::{Context, Request},
+        types::Pointer,
+        with_user, Acl,
+    };
+
+    async fn save_roles(
+        _req: Request,
+        ctx: Arc<Context>,
+        _arg: HashMap<String, String>,
+    ) -> Result<String, Rejection> {
+        let user = |id: &str| Pointer::new("_User", id);
+        let mut moderator = ctx.clone().object(ROLE);
+        moderator.set_data(doc! {"name": "Moderator"});
+        moderator.add_relation("users", vec![user("m")]);
+        moderator.save().await?;
+        let moderator = moderator.pointer().unwrap();
+
+        // Admins are granted whatever granted to moderators.
+        let mut admin = ctx.clone().object(ROLE);
+        admin.set_data(doc! {"name": "Admin"});
+        admin.add_relation("users", vec![user("a")]);
+        admin.save().await?;
+        let admin = admin.pointer().unwrap();
+        let mut m = ctx.clone().object(ROLE);
+        m.set_id(&moderator.object_id);
+        m.add_relation("roles", vec![admin.clone()]);
+        m.save().await?;
+
+        let mut roles = vec![];
+        for id in &["m", "a", "x"] {
+            roles.push(resolve(&*ctx.db, id).await?.join(","));
+        }
+        assert_eq!(roles, vec!["Moderator", "Admin,Moderator", ""]);
+
+        // Cycles of roles are fine.
+        let mut a = ctx.clone().object(ROLE);
+        a.set_id(&admin.object_id);
+        a.add_relation("roles", vec![moderator.clone()]);
+        a.save().await?;
+        let mut roles = resolve(&*ctx.db, "m").await?;
+        roles.sort();
+        assert_eq!(roles, vec!["Admin", "Moderator"]);
+
+        let mut ids = vec![];
+        let acls = vec![
+            ("Moderator", "w", "i"),
+            ("Moderator", "r", "w"),
+            ("Admin", "w", "r"),
+        ];
+        for (role, flag, public) in acls {
+            let mut acl = Acl::new();
+            match flag {
+                "r" => acl.set_role_readonly(role),
+                _ => acl.set_role_writable(role),
+            }
+            match public {
+                "i" => acl.set_public_invisiable(),
+                "r" => acl.set_public_readonly(),
+                _ => acl.set_public_writable(),
+            }
+            let mut obj = ctx.clone().object("Post");
+            obj.set_data(doc! {"title": "a"});
+            obj.set_acl(acl);
+            obj.save().await?;
+            ids.push(obj.pointer().unwrap().object_id);
+        }
+        Ok(ids.join(","))
+    }
+
+    #[tokio::test]
+    async fn test_role() {
+        let mut s = test_server().await;
+        s.define(
+            "roles",
+            Box::new(|req, ctx, arg| Box::pin(save_roles(req, ctx, arg))),
+        );
+        let api = s.routes().await;
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/functions/roles")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let ids = String::from_utf8_lossy(&resp.body()[..]).to_string();
+        let ids: Vec<&str> = ids.split(',').collect();
+
+        let retrieve1 = async move |api, uid, id| {
+            with_user!(uid, "GET")
+                .path(&format!("/classes/Post/{}", id))
+                .reply(api)
+                .await
+                .status()
+        };
+        let update1 = async move |api, uid, id| {
+            with_user!(uid, "PUT")
+                .path(&format!("/classes/Post/{}", id))
+                .json(&json!({"title": "b"}))
+                .reply(api)
+                .await
+                .status()
+        };
+        let (ok, not_found) = (StatusCode::OK, StatusCode::NOT_FOUND);
+
+        // Writable by moderators only, where admins inherit it.
+        assert_eq!(retrieve1(&api, "x", ids[0]).await, not_found);
+        assert_eq!(update1(&api, "m", ids[0]).await, ok);
+        assert_eq!(update1(&api, "a", ids[0]).await, ok);
+
+        // Read-only by moderators regardless of public.
+        assert_eq!(retrieve1(&api, "m", ids[1]).await, ok);
+        assert_eq!(update1(&api, "m", ids[1]).await, not_found);
+        assert_eq!(update1(&api, "x", ids[1]).await, ok);
+
+        // Writable by admins, including moderators by the cycle, and readable by others.
+        assert_eq!(update1(&api, "x", ids[2]).await, not_found);
+        assert_eq!(retrieve1(&api, "x", ids[2]).await, ok);
+        assert_eq!(update1(&api, "a", ids[2]).await, ok);
+        assert_eq!(update1(&api, "m", ids[2]).await, ok);
+    }
+}
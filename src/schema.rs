@@ -0,0 +1,1467 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use mongodb::bson::{doc, Bson, Document};
+use warp::{Rejection, Reply};
+
+use crate::{
+    acl,
+    database::{Database, Operation, ACL, CREATED_AT, OBJECT_ID, UPDATED_AT},
+    error::{bad_request, conflict, forbidden, not_found, unauthorized},
+    query::{Constraint, Filter, Order, Query},
+    role,
+    server::{Context, Request},
+    session,
+    types::{self, GeoPoint, Pointer},
+    user::UserKind,
+    validator::ClassName,
+};
+
+/// Class storing the schema of each class, one object per class.
+pub const SCHEMA: &'static str = "_SCHEMA";
+
+/// Type of field, encoded as `{"type": TYPE}`, with `targetClass` for `Pointer` and `Relation`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldType {
+    /// String.
+    String,
+    /// Integer or floating point number.
+    Number,
+    /// Boolean.
+    Boolean,
+    /// Date, see `types::decode`.
+    Date,
+    /// Binary data, see `types::decode`.
+    Bytes,
+    /// Point on the earth, see `types::GeoPoint`.
+    GeoPoint,
+    /// File uploaded, see `types::decode`.
+    File,
+    /// Array of any values.
+    Array,
+    /// Object of any fields, i.e. document embedded.
+    Object,
+    /// Pointer to an object of the class.
+    Pointer(String),
+    /// Relation to objects of the class.
+    Relation(String),
+}
+
+impl FieldType {
+    /// Infer the type of value `v`, which is `None` for null and values of unsupported types.
+    pub fn of(v: &Bson) -> Option<Self> {
+        Some(match v {
+            Bson::String(_) => FieldType::String,
+            Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) => FieldType::Number,
+            Bson::Boolean(_) => FieldType::Boolean,
+            Bson::DateTime(_) => FieldType::Date,
+            Bson::Binary(_) => FieldType::Bytes,
+            Bson::Array(_) => FieldType::Array,
+            Bson::Document(_) if GeoPoint::from_bson(v).is_some() => FieldType::GeoPoint,
+            Bson::Document(d) => match (d.get_str(types::TYPE), d.get_str("className")) {
+                (Ok("File"), _) => FieldType::File,
+                (Ok("Pointer"), Ok(c)) => FieldType::Pointer(c.to_string()),
+                (Ok("Relation"), Ok(c)) => FieldType::Relation(c.to_string()),
+                _ => FieldType::Object,
+            },
+            _ => return None,
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            FieldType::String => "String",
+            FieldType::Number => "Number",
+            FieldType::Boolean => "Boolean",
+            FieldType::Date => "Date",
+            FieldType::Bytes => "Bytes",
+            FieldType::GeoPoint => "GeoPoint",
+            FieldType::File => "File",
+            FieldType::Array => "Array",
+            FieldType::Object => "Object",
+            FieldType::Pointer(_) => "Pointer",
+            FieldType::Relation(_) => "Relation",
+        }
+    }
+
+    fn from_doc(d: &Document) -> Option<Self> {
+        let target = || match d.get_str("targetClass") {
+            Ok(c) if types::class_name(c) => Some(c.to_string()),
+            _ => None,
+        };
+        Some(match d.get_str("type").ok()? {
+            "String" => FieldType::String,
+            "Number" => FieldType::Number,
+            "Boolean" => FieldType::Boolean,
+            "Date" => FieldType::Date,
+            "Bytes" => FieldType::Bytes,
+            "GeoPoint" => FieldType::GeoPoint,
+            "File" => FieldType::File,
+            "Array" => FieldType::Array,
+            "Object" => FieldType::Object,
+            "Pointer" => FieldType::Pointer(target()?),
+            "Relation" => FieldType::Relation(target()?),
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldType::Pointer(c) | FieldType::Relation(c) => write!(f, "{}<{}>", self.name(), c),
+            t => write!(f, "{}", t.name()),
+        }
+    }
+}
+
+/// Field of class, encoded as `{"type": TYPE, "required": BOOL, "defaultValue": VALUE}`
+/// where the last two are optional.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    /// Type of the field.
+    pub field_type: FieldType,
+    /// Whether the field should be set on creation and cannot be removed.
+    pub required: bool,
+    /// Value of the field if missing on creation.
+    pub default: Option<Bson>,
+}
+
+impl Field {
+    /// Field of `field_type` that is neither required nor defaulted.
+    pub fn new(field_type: FieldType) -> Self {
+        Self {
+            field_type,
+            required: false,
+            default: None,
+        }
+    }
+
+    fn from_doc(key: &str, d: &Document) -> Result<Self, Rejection> {
+        let field_type = match FieldType::from_doc(d) {
+            Some(t) => t,
+            None => return bad_request(format!("Invalid type of field {}", key)),
+        };
+        let required = match d.get("required") {
+            None => false,
+            Some(Bson::Boolean(b)) => *b,
+            Some(_) => {
+                return bad_request(format!("Expect a boolean of required of field {}", key))
+            }
+        };
+        let default = match d.get("defaultValue") {
+            None | Some(Bson::Null) => None,
+            Some(v) if FieldType::of(v).as_ref() == Some(&field_type) => Some(v.clone()),
+            Some(_) => {
+                return bad_request(format!(
+                    "Expect default value of {} of field {}",
+                    field_type, key
+                ))
+            }
+        };
+        Ok(Self {
+            field_type,
+            required,
+            default,
+        })
+    }
+}
+
+impl From<Field> for Document {
+    fn from(f: Field) -> Self {
+        let mut d = doc! {"type": f.field_type.name()};
+        if let FieldType::Pointer(c) | FieldType::Relation(c) = f.field_type {
+            d.insert("targetClass", c);
+        }
+        if f.required {
+            d.insert("required", true);
+        }
+        if let Some(v) = f.default {
+            d.insert("defaultValue", v);
+        }
+        d
+    }
+}
+
+/// Kinds of operations on objects of a class restricted by class-level permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClassOperation {
+    /// Retrieve an object by id.
+    Get,
+    /// Query objects.
+    Find,
+    /// Count objects.
+    Count,
+    /// Create objects.
+    Create,
+    /// Update objects.
+    Update,
+    /// Delete objects.
+    Delete,
+    /// Write fields not in the schema yet.
+    AddField,
+}
+
+impl ClassOperation {
+    const ALL: &'static [Self] = &[
+        ClassOperation::Get,
+        ClassOperation::Find,
+        ClassOperation::Count,
+        ClassOperation::Create,
+        ClassOperation::Update,
+        ClassOperation::Delete,
+        ClassOperation::AddField,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            ClassOperation::Get => "get",
+            ClassOperation::Find => "find",
+            ClassOperation::Count => "count",
+            ClassOperation::Create => "create",
+            ClassOperation::Update => "update",
+            ClassOperation::Delete => "delete",
+            ClassOperation::AddField => "addField",
+        }
+    }
+}
+
+/// Users allowed to perform an operation, encoded as
+/// `{"*": true, "requiresAuthentication": true, USER_ID: true, ...}`.
+///
+/// The default one allows no one but Master.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Permission {
+    /// Whether allowed to everyone, including guests.
+    pub public: bool,
+    /// Whether allowed to all users logged in.
+    pub authenticated: bool,
+    /// Ids of users allowed.
+    pub users: BTreeSet<String>,
+}
+
+impl Permission {
+    /// Permission allowing everyone.
+    pub fn public() -> Self {
+        Self {
+            public: true,
+            ..Self::default()
+        }
+    }
+
+    /// Permission allowing all users logged in.
+    pub fn authenticated() -> Self {
+        Self {
+            authenticated: true,
+            ..Self::default()
+        }
+    }
+
+    /// Allow user of `user_id` as well.
+    pub fn allow(mut self, user_id: impl Into<String>) -> Self {
+        self.users.insert(user_id.into());
+        self
+    }
+
+    /// Check if `user` is allowed.
+    pub fn allows(&self, user: &UserKind) -> bool {
+        match user {
+            UserKind::Master => true,
+            UserKind::Client(t) => self.public || self.authenticated || self.users.contains(&t.id),
+            UserKind::Guest => self.public,
+        }
+    }
+
+    fn from_doc(op: &str, d: &Document) -> Result<Self, Rejection> {
+        let mut p = Self::default();
+        for (k, v) in d {
+            match (k.as_str(), v) {
+                (_, Bson::Boolean(false)) => {}
+                ("*", Bson::Boolean(true)) => p.public = true,
+                ("requiresAuthentication", Bson::Boolean(true)) => p.authenticated = true,
+                (id, Bson::Boolean(true)) => {
+                    p.users.insert(id.to_string());
+                }
+                _ => return bad_request(format!("Expect booleans in permission of {}", op)),
+            }
+        }
+        Ok(p)
+    }
+}
+
+impl From<Permission> for Document {
+    fn from(p: Permission) -> Self {
+        let mut d = Document::new();
+        if p.public {
+            d.insert("*", true);
+        }
+        if p.authenticated {
+            d.insert("requiresAuthentication", true);
+        }
+        for id in p.users {
+            d.insert(id, true);
+        }
+        d
+    }
+}
+
+/// Class-level permissions, encoded as `{OPERATION: PERMISSION, ...}` with operations
+/// `get`, `find`, `count`, `create`, `update`, `delete` and `addField`.
+///
+/// Operations not specified are allowed to everyone, subject to ACL of each object.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClassPermissions(BTreeMap<ClassOperation, Permission>);
+
+impl ClassPermissions {
+    /// Class-level permissions allowing everyone to perform any operation.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the permission of operation `op`.
+    pub fn set(&mut self, op: ClassOperation, p: Permission) {
+        self.0.insert(op, p);
+    }
+
+    /// Get the permission of operation `op` if specified.
+    pub fn get(&self, op: ClassOperation) -> Option<&Permission> {
+        self.0.get(&op)
+    }
+
+    /// Check if no operation is specified.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check if `user` is allowed to perform operation `op`.
+    pub fn allows(&self, op: ClassOperation, user: &UserKind) -> bool {
+        self.get(op).map_or(true, |p| p.allows(user))
+    }
+
+    fn from_doc(d: &Document) -> Result<Self, Rejection> {
+        let mut r = Self::new();
+        for (k, v) in d {
+            let op = match ClassOperation::ALL
+                .iter()
+                .find(|op| op.name() == k.as_str())
+            {
+                Some(op) => *op,
+                None => return bad_request(format!("Unknown operation {}", k)),
+            };
+            match v {
+                Bson::Document(p) => r.set(op, Permission::from_doc(k, p)?),
+                _ => return bad_request(format!("Expect an object of permission of {}", k)),
+            }
+        }
+        Ok(r)
+    }
+}
+
+impl From<ClassPermissions> for Document {
+    fn from(r: ClassPermissions) -> Self {
+        r.0.into_iter()
+            .map(|(op, p)| (op.name().to_string(), Bson::Document(p.into())))
+            .collect()
+    }
+}
+
+/// Fields of a class hidden from users in responses, except the owner of each object
+/// and users of certain roles.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProtectedFields {
+    /// Fields hidden.
+    pub fields: Vec<String>,
+    /// Names of roles whose users can see the fields.
+    pub roles: Vec<String>,
+    /// Field of pointer to the owner of each object, who can see the fields, or `objectId`
+    /// for users themselves in `_User`.
+    pub owner: Option<String>,
+}
+
+impl ProtectedFields {
+    /// Hide `fields` from everyone but Master.
+    pub fn new<T: Into<String>>(fields: impl IntoIterator<Item = T>) -> Self {
+        Self {
+            fields: fields.into_iter().map(Into::into).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Show the fields to users of role `name`.
+    pub fn except_role(mut self, name: impl Into<String>) -> Self {
+        self.roles.push(name.into());
+        self
+    }
+
+    /// Show the fields to the owner of each object referenced by pointer field `key`,
+    /// or the user itself if `key` is `objectId` in `_User`.
+    pub fn except_owner(mut self, key: impl Into<String>) -> Self {
+        self.owner = Some(key.into());
+        self
+    }
+
+    /// Check if the fields of `doc` are visible to `user`.
+    fn visible(&self, doc: &Document, user: &UserKind) -> bool {
+        let t = match user {
+            UserKind::Master => return true,
+            UserKind::Client(t) => t,
+            UserKind::Guest => return false,
+        };
+        if t.roles.iter().any(|r| self.roles.contains(r)) {
+            return true;
+        }
+        match self.owner.as_deref() {
+            Some(OBJECT_ID) => doc.get_str(OBJECT_ID) == Ok(t.id.as_str()),
+            Some(k) => doc
+                .get(k)
+                .and_then(Pointer::from_bson)
+                .map_or(false, |p| p.object_id == t.id),
+            None => false,
+        }
+    }
+}
+
+/// Field never returned to anyone, i.e. password of `_User`.
+const PASSWORD: &'static str = "password";
+
+/// Remove fields of `doc` in `class` protected from `user` before responding, where
+/// `password` and refresh tokens of sessions are always removed, and ACL is given as `ACL` the
+/// same as clients set it.
+pub(crate) fn protect(ctx: &Context, class: &str, user: &UserKind, mut doc: Document) -> Document {
+    doc.remove(PASSWORD);
+    if class == session::SESSION {
+        doc = session::hide(doc);
+    }
+    if doc.contains_key(ACL) {
+        let client = Document::from(acl::of(&doc));
+        doc.remove(ACL);
+        doc.insert(acl::CLIENT_ACL, client);
+    }
+    if let Some(p) = ctx.protected_fields.get(class) {
+        if !p.visible(&doc, user) {
+            for k in p.fields.iter() {
+                doc.remove(k);
+            }
+        }
+    }
+    doc
+}
+
+/// Built-in classes whose objects users may reach by subqueries and `include`, with protected
+/// fields and `password` still hidden. Other classes starting with `_` are only for Master.
+const REACHABLE_BUILTIN: [&'static str; 2] = ["_User", role::ROLE];
+
+/// Check if objects of `class` may be reached by `user` through subqueries and `include`.
+pub(crate) fn reachable(class: &str, user: &UserKind) -> Result<(), Rejection> {
+    let builtin = class.starts_with('_') && !REACHABLE_BUILTIN.contains(&class);
+    if builtin && !matches!(user, UserKind::Master) {
+        return forbidden(format!("Cannot query {}", class));
+    }
+    Ok(())
+}
+
+/// Whether field `key` of `class`, or the field it is in, is hidden from `user` in any object,
+/// where fields shown only to the owner of each object count as hidden.
+fn hidden(ctx: &Context, class: &str, key: &str, user: &UserKind) -> bool {
+    let root = key.split('.').next().unwrap_or(key);
+    if root == PASSWORD {
+        return true;
+    }
+    let p = match ctx.protected_fields.get(class) {
+        Some(p) if p.fields.iter().any(|k| k == root) => p,
+        _ => return false,
+    };
+    match user {
+        UserKind::Master => false,
+        UserKind::Client(t) => !t.roles.iter().any(|r| p.roles.contains(r)),
+        UserKind::Guest => true,
+    }
+}
+
+/// Check `query` on `class` before performed on behalf of `user`, which may neither filter nor
+/// sort by fields hidden from it, see `hidden`, and may reach by subqueries only classes allowed
+/// by `reachable` and their class-level permissions, i.e. `find` for `$inQuery` and `get` for
+/// `$relatedTo`.
+pub(crate) async fn check_query(
+    ctx: &Context,
+    class: &str,
+    query: &Query,
+    user: &UserKind,
+) -> Result<(), Rejection> {
+    check_fields(ctx, class, &query.filter, user)?;
+    for (k, _) in query.order.iter() {
+        if hidden(ctx, class, k, user) {
+            return forbidden(format!("Cannot sort by {}", k));
+        }
+    }
+    let mut classes = vec![];
+    subqueries(ctx, &query.filter, user, &mut classes)?;
+    for (class, op) in classes {
+        authorize(ctx, &class, op, user).await?;
+    }
+    Ok(())
+}
+
+/// Check subqueries in `filter` as `check_query` but class-level permissions, which are
+/// collected into `classes` instead.
+fn subqueries(
+    ctx: &Context,
+    filter: &Filter,
+    user: &UserKind,
+    classes: &mut Vec<(String, ClassOperation)>,
+) -> Result<(), Rejection> {
+    match filter {
+        Filter::And(v) | Filter::Or(v) => {
+            v.iter().try_for_each(|f| subqueries(ctx, f, user, classes))
+        }
+        Filter::Field(_, Constraint::InQuery(class, f)) => {
+            reachable(class, user)?;
+            check_fields(ctx, class, f, user)?;
+            classes.push((class.clone(), ClassOperation::Find));
+            subqueries(ctx, f, user, classes)
+        }
+        Filter::Field(..) => Ok(()),
+        Filter::RelatedTo(p, key) => {
+            reachable(&p.class_name, user)?;
+            if hidden(ctx, &p.class_name, key, user) {
+                return forbidden(format!("Cannot query by {}", key));
+            }
+            classes.push((p.class_name.clone(), ClassOperation::Get));
+            Ok(())
+        }
+    }
+}
+
+/// Check that `filter` on `class` constrains no fields hidden from `user`, see `hidden`.
+fn check_fields(
+    ctx: &Context,
+    class: &str,
+    filter: &Filter,
+    user: &UserKind,
+) -> Result<(), Rejection> {
+    match filter {
+        Filter::And(v) | Filter::Or(v) => {
+            v.iter().try_for_each(|f| check_fields(ctx, class, f, user))
+        }
+        Filter::Field(k, _) if hidden(ctx, class, k, user) => {
+            forbidden(format!("Cannot query by {}", k))
+        }
+        Filter::Field(..) | Filter::RelatedTo(..) => Ok(()),
+    }
+}
+
+/// Schema of a class, encoded as
+/// `{"className": CLASS, "fields": {KEY: FIELD, ...}, "classLevelPermissions": {...}}`.
+///
+/// Writes to objects of the class are checked against it, where types of fields
+/// unknown are inferred and recorded on first write.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    /// Name of the class.
+    pub class_name: String,
+    /// Fields of the class besides `objectId`, `createdAt`, `updatedAt` and `acl`.
+    pub fields: BTreeMap<String, Field>,
+    /// Class-level permissions, which take precedence over those set on `Server` unless empty.
+    pub permissions: ClassPermissions,
+}
+
+/// Whether `key` is one of or in the fields managed by the server.
+fn reserved(key: &str) -> bool {
+    [OBJECT_ID, CREATED_AT, UPDATED_AT, ACL]
+        .iter()
+        .any(|r| key == *r || key.starts_with(&format!("{}.", r)))
+}
+
+fn field_name(key: &str) -> Result<(), Rejection> {
+    if key.is_empty() || key.contains('.') || key.starts_with('$') || reserved(key) {
+        bad_request(format!("Invalid field name {}", key))
+    } else {
+        Ok(())
+    }
+}
+
+impl Schema {
+    /// Schema of `class_name` without any field.
+    pub fn new(class_name: impl Into<String>) -> Self {
+        Self {
+            class_name: class_name.into(),
+            fields: BTreeMap::new(),
+            permissions: ClassPermissions::new(),
+        }
+    }
+
+    /// Parse the schema encoded in `doc`.
+    pub fn from_doc(doc: &Document) -> Result<Self, Rejection> {
+        let class_name = match doc.get_str("className") {
+            Ok(c) if types::class_name(c) => c,
+            _ => return bad_request("Expect className of schema"),
+        };
+        let mut schema = Self::new(class_name);
+        match doc.get("classLevelPermissions") {
+            None | Some(Bson::Null) => {}
+            Some(Bson::Document(d)) => schema.permissions = ClassPermissions::from_doc(d)?,
+            Some(_) => return bad_request("Expect an object of classLevelPermissions"),
+        }
+        let fields = match doc.get("fields") {
+            None => return Ok(schema),
+            Some(Bson::Document(d)) => d,
+            Some(_) => return bad_request("Expect an object of fields"),
+        };
+        for (k, v) in fields {
+            field_name(k)?;
+            match v {
+                Bson::Document(d) => {
+                    schema.fields.insert(k.clone(), Field::from_doc(k, d)?);
+                }
+                _ => return bad_request(format!("Expect an object of field {}", k)),
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Check `doc` to create an object, or to update one unless `create`, returning fields
+    /// unknown with types inferred. Missing fields with default values are filled when creating.
+    fn check(
+        &self,
+        doc: &mut Document,
+        create: bool,
+    ) -> Result<Vec<(String, FieldType)>, Rejection> {
+        let mut added: Vec<(String, FieldType)> = vec![];
+        for (k, op) in Operation::parse(doc.clone())? {
+            if reserved(&k) {
+                continue;
+            }
+            // Updating a nested field such as `a.b` requires `a` to be an object.
+            let (name, t) = match k.find('.') {
+                Some(i) => (&k[..i], Some(FieldType::Object)),
+                None => (
+                    k.as_str(),
+                    match &op {
+                        Operation::Set(v) => FieldType::of(v),
+                        Operation::Increment(_) => Some(FieldType::Number),
+                        Operation::Add(_) | Operation::AddUnique(_) | Operation::Remove(_) => {
+                            Some(FieldType::Array)
+                        }
+                        Operation::Delete => None,
+                        Operation::AddRelation(v) | Operation::RemoveRelation(v) => {
+                            v.first().map(|p| FieldType::Relation(p.class_name.clone()))
+                        }
+                    },
+                ),
+            };
+            match (self.fields.get(name), t) {
+                (Some(f), None) if f.required && name == k => {
+                    if let Operation::Delete | Operation::Set(Bson::Null) = op {
+                        return bad_request(format!("Field {} is required", name));
+                    }
+                }
+                (Some(f), Some(t)) if f.field_type != t => {
+                    return bad_request(format!(
+                        "Expect {} of field {}, got {}",
+                        f.field_type, name, t
+                    ));
+                }
+                (None, Some(t)) => match added.iter().find(|(k, _)| k == name) {
+                    Some((_, x)) if *x != t => {
+                        return bad_request(format!("Expect {} of field {}, got {}", x, name, t));
+                    }
+                    Some(_) => {}
+                    None => {
+                        field_name(name)?;
+                        added.push((name.to_string(), t));
+                    }
+                },
+                _ => {}
+            }
+        }
+        if create {
+            for (k, f) in self.fields.iter() {
+                match doc.get(k) {
+                    None | Some(Bson::Null) => match &f.default {
+                        Some(v) => {
+                            doc.insert(k.clone(), v.clone());
+                        }
+                        None if f.required => {
+                            return bad_request(format!("Field {} is required", k));
+                        }
+                        None => {}
+                    },
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(added)
+    }
+}
+
+impl From<Schema> for Document {
+    fn from(s: Schema) -> Self {
+        let fields: Document = s
+            .fields
+            .into_iter()
+            .map(|(k, f)| (k, Bson::Document(f.into())))
+            .collect();
+        let permissions: Document = s.permissions.into();
+        doc! {
+            "className": s.class_name,
+            "fields": fields,
+            "classLevelPermissions": permissions,
+        }
+    }
+}
+
+/// How long schemas are cached, after which modifications by other server instances sharing
+/// the database take effect.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Cache of schemas by class, `None` if not stored, so that checking every request does not
+/// hit the database. Entries of schemas modified by this server are dropped at once.
+#[derive(Default)]
+pub(crate) struct SchemaCache {
+    entries: Mutex<HashMap<String, (Option<Schema>, Instant)>>,
+}
+
+impl SchemaCache {
+    fn cached(&self, class: &str) -> Option<Option<Schema>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(class) {
+            Some((s, t)) if t.elapsed() < CACHE_TTL => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    /// Schema of `class` stored in `db`.
+    async fn get(&self, db: &dyn Database, class: &str) -> Result<Option<Schema>, Rejection> {
+        if let Some(s) = self.cached(class) {
+            return Ok(s);
+        }
+        let s = load(db, class).await?.map(|(_, s)| s);
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(class.to_string(), (s.clone(), Instant::now()));
+        Ok(s)
+    }
+
+    /// Drop the schema of `class` once modified.
+    fn invalidate(&self, class: &str) {
+        self.entries.lock().unwrap().remove(class);
+    }
+}
+
+/// Schema of `class` stored in `db` together with its object id.
+async fn load(db: &dyn Database, class: &str) -> Result<Option<(String, Schema)>, Rejection> {
+    let query = Query::from(doc! {"className": class});
+    let v = db.retrieve(SCHEMA, query, UserKind::Master).await?;
+    match v.first() {
+        Some(d) => {
+            let id = d.get_str(OBJECT_ID).unwrap_or_default().to_string();
+            Ok(Some((id, Schema::from_doc(d)?)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reject `user` not allowed to perform `op` on `class` by `permissions` stored in its schema,
+/// or those set on `Server` if empty.
+fn permit(
+    ctx: &Context,
+    class: &str,
+    permissions: &ClassPermissions,
+    op: ClassOperation,
+    user: &UserKind,
+) -> Result<(), Rejection> {
+    let permissions = match ctx.class_permissions.get(class) {
+        Some(p) if permissions.is_empty() => p,
+        _ => permissions,
+    };
+    if permissions.allows(op, user) {
+        return Ok(());
+    }
+    let msg = format!("Permission denied to {} in class {}", op.name(), class);
+    match user {
+        UserKind::Guest => unauthorized(msg),
+        _ => forbidden(msg),
+    }
+}
+
+/// Class-level permissions stored in the schema of `class`.
+async fn permissions(ctx: &Context, class: &str) -> Result<ClassPermissions, Rejection> {
+    Ok(ctx
+        .schemas
+        .get(&*ctx.db, class)
+        .await?
+        .map(|s| s.permissions)
+        .unwrap_or_default())
+}
+
+/// Reject `user` not allowed to perform `op` on `class` by class-level permissions.
+pub(crate) async fn authorize(
+    ctx: &Context,
+    class: &str,
+    op: ClassOperation,
+    user: &UserKind,
+) -> Result<(), Rejection> {
+    if let UserKind::Master = user {
+        return Ok(());
+    }
+    permit(ctx, class, &permissions(ctx, class).await?, op, user)
+}
+
+/// Whether `user` is allowed to perform `op` on `class` by class-level permissions.
+pub(crate) async fn allows(
+    ctx: &Context,
+    class: &str,
+    op: ClassOperation,
+    user: &UserKind,
+) -> Result<bool, Rejection> {
+    if let UserKind::Master = user {
+        return Ok(true);
+    }
+    let permissions = permissions(ctx, class).await?;
+    Ok(permit(ctx, class, &permissions, op, user).is_ok())
+}
+
+/// Check `doc` to create an object of `class` by `user`, or to update one unless `create`,
+/// against the schema stored, and return fields unknown with types inferred if `user` is
+/// allowed to add them, which are recorded by `record` once the object is written.
+pub(crate) async fn validate(
+    ctx: &Context,
+    class: &str,
+    doc: &mut Document,
+    create: bool,
+    user: &UserKind,
+) -> Result<Vec<(String, FieldType)>, Rejection> {
+    if class == SCHEMA {
+        return Ok(vec![]);
+    }
+    let schema = match ctx.schemas.get(&*ctx.db, class).await? {
+        Some(s) => s,
+        None => Schema::new(class),
+    };
+    let added = schema.check(doc, create)?;
+    if !added.is_empty() {
+        permit(
+            ctx,
+            class,
+            &schema.permissions,
+            ClassOperation::AddField,
+            user,
+        )?;
+    }
+    Ok(added)
+}
+
+/// Record fields `added` of `class` returned by `validate`, each unless recorded already,
+/// so that the type recorded first is kept by concurrent writes.
+pub(crate) async fn record(
+    ctx: &Context,
+    class: &str,
+    added: Vec<(String, FieldType)>,
+) -> Result<(), Rejection> {
+    if added.is_empty() {
+        return Ok(());
+    }
+    trace!("infer fields of {}: {:?}", class, added);
+    let db = &*ctx.db;
+    let id = match load(db, class).await? {
+        Some((id, _)) => id,
+        None => {
+            let mut schema = Schema::new(class);
+            for (k, t) in added.iter() {
+                schema.fields.insert(k.clone(), Field::new(t.clone()));
+            }
+            // Failed if created by others in between, to which the fields are added then.
+            match db.create(SCHEMA, schema.into(), UserKind::Master).await {
+                Ok(_) => {
+                    ctx.schemas.invalidate(class);
+                    return Ok(());
+                }
+                Err(e) => match load(db, class).await? {
+                    Some((id, _)) => id,
+                    None => return Err(e),
+                },
+            }
+        }
+    };
+    for (k, t) in added {
+        let field = Document::from(Field::new(t));
+        db.set_if_missing(SCHEMA, &id, &format!("fields.{}", k), field.into())
+            .await?;
+    }
+    ctx.schemas.invalidate(class);
+    Ok(())
+}
+
+fn master_only(user: &UserKind) -> Result<(), Rejection> {
+    match user {
+        UserKind::Master => Ok(()),
+        _ => unauthorized("Only Master is allowed to manage schemas"),
+    }
+}
+
+/// List schemas of all classes as `{"results": [...]}`, used by RESTFul API.
+pub async fn list(req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejection> {
+    master_only(&req.user)?;
+    let query = Query::default().order_by("className", Order::Ascending);
+    let mut results = vec![];
+    for d in ctx.db.retrieve(SCHEMA, query, UserKind::Master).await? {
+        results.push(Document::from(Schema::from_doc(&d)?));
+    }
+    types::to_json(doc! {"results": results})
+}
+
+/// Retrieve the schema of `class`, used by RESTFul API.
+pub async fn retrieve(
+    class: ClassName,
+    req: Request,
+    ctx: Arc<Context>,
+) -> Result<impl Reply, Rejection> {
+    master_only(&req.user)?;
+    match load(&*ctx.db, class.as_str()).await? {
+        Some((_, s)) => types::to_json(Document::from(s)),
+        None => not_found(format!("Schema of {} not found", class.as_str())),
+    }
+}
+
+/// Create the schema of `class` with body of `fields` and `classLevelPermissions`, used by
+/// RESTFul API.
+pub async fn create(
+    class: ClassName,
+    req: Request,
+    ctx: Arc<Context>,
+) -> Result<impl Reply, Rejection> {
+    master_only(&req.user)?;
+    let mut body = req.body.unwrap_or_default();
+    match body.get_str("className") {
+        Ok(c) if c != class.as_str() => return bad_request("Class name mismatch"),
+        _ => {}
+    }
+    body.insert("className", class.as_str());
+    let schema = Schema::from_doc(&body)?;
+    if load(&*ctx.db, class.as_str()).await?.is_some() {
+        return conflict(format!("Schema of {} exists", class.as_str()));
+    }
+    ctx.db
+        .create(SCHEMA, schema.clone().into(), UserKind::Master)
+        .await?;
+    ctx.schemas.invalidate(class.as_str());
+    Ok(warp::reply::with_status(
+        types::to_json(Document::from(schema))?,
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+/// Update the schema of `class` with body of `fields` and `classLevelPermissions`, used by
+/// RESTFul API.
+///
+/// Fields are added or replaced without changing their types, and removed from the schema
+/// by `{"__op": "Delete"}`, while data of objects is kept as is. Class-level permissions
+/// are replaced if given.
+pub async fn update(
+    class: ClassName,
+    req: Request,
+    ctx: Arc<Context>,
+) -> Result<impl Reply, Rejection> {
+    master_only(&req.user)?;
+    let (id, mut schema) = match load(&*ctx.db, class.as_str()).await? {
+        Some(s) => s,
+        None => return not_found(format!("Schema of {} not found", class.as_str())),
+    };
+    let body = req.body.unwrap_or_default();
+    let fields = match body.get("fields") {
+        None => Document::new(),
+        Some(Bson::Document(d)) => d.clone(),
+        Some(_) => return bad_request("Expect an object of fields"),
+    };
+    match body.get("classLevelPermissions") {
+        None => {}
+        Some(Bson::Document(d)) => schema.permissions = ClassPermissions::from_doc(d)?,
+        Some(_) => return bad_request("Expect an object of classLevelPermissions"),
+    }
+    for (k, v) in fields {
+        field_name(&k)?;
+        let d = match v {
+            Bson::Document(d) => d,
+            _ => return bad_request(format!("Expect an object of field {}", k)),
+        };
+        if d.get_str("__op") == Ok("Delete") {
+            if schema.fields.remove(&k).is_none() {
+                return bad_request(format!("Field {} not found", k));
+            }
+            continue;
+        }
+        let f = Field::from_doc(&k, &d)?;
+        match schema.fields.get(&k) {
+            Some(old) if old.field_type != f.field_type => {
+                return bad_request(format!("Field {} exists with type {}", k, old.field_type));
+            }
+            _ => {
+                schema.fields.insert(k, f);
+            }
+        }
+    }
+    let mut d = Document::from(schema.clone());
+    d.remove("className");
+    ctx.db.update(SCHEMA, &id, d, UserKind::Master).await?;
+    ctx.schemas.invalidate(class.as_str());
+    types::to_json(Document::from(schema))
+}
+
+/// Delete the schema of `class` which should have no objects, used by RESTFul API.
+pub async fn delete(
+    class: ClassName,
+    req: Request,
+    ctx: Arc<Context>,
+) -> Result<impl Reply, Rejection> {
+    master_only(&req.user)?;
+    let (id, _) = match load(&*ctx.db, class.as_str()).await? {
+        Some(s) => s,
+        None => return not_found(format!("Schema of {} not found", class.as_str())),
+    };
+    let n = ctx
+        .db
+        .count(class.as_str(), Filter::default(), UserKind::Master)
+        .await?;
+    if n > 0 {
+        return bad_request(format!("Class {} is not empty", class.as_str()));
+    }
+    ctx.db.delete(SCHEMA, &id, UserKind::Master).await?;
+    ctx.schemas.invalidate(class.as_str());
+    Ok("{}".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+    use warp::hyper::StatusCode;
+
+    use super::super::tests::{escape, test_api, test_server, TEST_SERVER_KEY};
+    use super::{ClassOperation, ClassPermissions, Permission, ProtectedFields};
+    use crate::with_user;
+
+    #[tokio::test]
+    async fn test_schema() {
+        let api = test_api().await;
+        let request1 = async move |api, method, path: &str, body: Value| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("x-parse-master-key", TEST_SERVER_KEY)
+                .json(&body)
+                .reply(api)
+                .await
+        };
+        let json1 = |body: &[u8]| serde_json::from_slice::<Value>(body).unwrap();
+
+        // Only Master can manage schemas.
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/schemas")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = request1(
+            &api,
+            "POST",
+            "/schemas/Song",
+            json!({"fields": {
+                "title": {"type": "String", "required": true},
+                "plays": {"type": "Number", "defaultValue": 0},
+                "album": {"type": "Pointer", "targetClass": "Album"},
+            }}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = json1(resp.body());
+        assert_eq!(body["className"], json!("Song"));
+        assert_eq!(
+            body["fields"]["album"],
+            json!({"type": "Pointer", "targetClass": "Album"})
+        );
+        let resp = request1(&api, "POST", "/schemas/Song", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Invalid fields.
+        for fields in vec![
+            json!({"a": {"type": "Unknown"}}),
+            json!({"a": {"type": "Pointer"}}),
+            json!({"a": {"type": "String", "defaultValue": 1}}),
+            json!({"a": {"type": "String", "required": "yes"}}),
+            json!({"objectId": {"type": "String"}}),
+            json!({"a.b": {"type": "String"}}),
+        ] {
+            let resp = request1(
+                &api,
+                "POST",
+                "/schemas/Invalid",
+                json!({ "fields": fields }),
+            )
+            .await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // Types of fields are checked on writes, with required and default ones on creation.
+        let create1 =
+            async move |api, body: Value| request1(api, "POST", "/classes/Song", body).await;
+        let resp = create1(&api, json!({"plays": 1})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = create1(&api, json!({"title": 1})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let album = json!({"__type": "Pointer", "className": "Artist", "objectId": "x"});
+        let resp = create1(&api, json!({"title": "a", "album": album})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = create1(&api, json!({"title": "a", "tags": ["x"]})).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = json1(resp.body());
+        assert_eq!(body["plays"], json!(0));
+        let path = format!("/classes/Song/{}", body["objectId"].as_str().unwrap());
+        let resp = request1(
+            &api,
+            "PUT",
+            &path,
+            json!({"plays": {"__op": "Increment", "amount": 1}}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = request1(&api, "PUT", &path, json!({"title": {"__op": "Delete"}})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = request1(&api, "PUT", &path, json!({"title": null})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = request1(
+            &api,
+            "PUT",
+            &path,
+            json!({"tags": {"__op": "Increment", "amount": 1}}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = request1(
+            &api,
+            "PUT",
+            &path,
+            json!({"tags": {"__op": "AddUnique", "objects": ["y"]}}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = request1(&api, "PUT", &path, json!({"meta.a": 1, "year": 2020})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = request1(&api, "PUT", &path, json!({"year": "2020"})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Fields inferred are recorded, unless the writes fail.
+        let missing = "/classes/Song/000000000000000000000000";
+        let resp = request1(&api, "PUT", missing, json!({"ghost": 1})).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = request1(&api, "GET", "/schemas/Song", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json1(resp.body());
+        assert_eq!(body["fields"]["ghost"], Value::Null);
+        assert_eq!(body["fields"]["tags"], json!({"type": "Array"}));
+        assert_eq!(body["fields"]["meta"], json!({"type": "Object"}));
+        assert_eq!(body["fields"]["year"], json!({"type": "Number"}));
+        assert_eq!(
+            body["fields"]["title"],
+            json!({"type": "String", "required": true})
+        );
+
+        // Classes are inferred on first write as well.
+        let resp = request1(&api, "POST", "/classes/Album", json!({"name": "b"})).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = request1(&api, "GET", "/schemas", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json1(resp.body());
+        let names: Vec<&Value> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| &s["className"])
+            .collect();
+        assert_eq!(names, vec![&json!("Album"), &json!("Song")]);
+
+        // Add, replace and remove fields without changing types.
+        let resp = request1(
+            &api,
+            "PUT",
+            "/schemas/Song",
+            json!({"fields": {"year": {"type": "String"}}}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = request1(
+            &api,
+            "PUT",
+            "/schemas/Song",
+            json!({"fields": {
+                "title": {"type": "String"},
+                "year": {"__op": "Delete"},
+                "rating": {"type": "Number", "required": true},
+            }}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = json1(resp.body());
+        assert_eq!(body["fields"]["title"], json!({"type": "String"}));
+        assert_eq!(body["fields"]["year"], Value::Null);
+        let resp = create1(&api, json!({"rating": 5})).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = request1(&api, "PUT", "/schemas/Nowhere", json!({"fields": {}})).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Only schemas of empty classes can be deleted.
+        let resp = request1(&api, "DELETE", "/schemas/Song", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = request1(&api, "POST", "/schemas/Empty", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = request1(&api, "DELETE", "/schemas/Empty", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = request1(&api, "GET", "/schemas/Empty", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_class_permissions() {
+        let mut s = test_server().await;
+        let mut p = ClassPermissions::new();
+        p.set(ClassOperation::Create, Permission::authenticated());
+        p.set(ClassOperation::Find, Permission::default());
+        p.set(ClassOperation::Get, Permission::public());
+        s.set_class_permissions("Order", p);
+        let api = s.routes().await;
+
+        let guest1 = async move |api, method, path: &str, body: Value| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .json(&body)
+                .reply(api)
+                .await
+        };
+        let user1 = async move |api, uid, method, path: &str, body: Value| {
+            with_user!(uid, method)
+                .path(path)
+                .json(&body)
+                .reply(api)
+                .await
+        };
+        let master1 = async move |api, method, path: &str, body: Value| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("x-parse-master-key", TEST_SERVER_KEY)
+                .json(&body)
+                .reply(api)
+                .await
+        };
+
+        // Permissions set on server.
+        let resp = guest1(&api, "POST", "/classes/Order", json!({"name": "a"})).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = user1(&api, "foo", "POST", "/classes/Order", json!({"name": "a"})).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        let path = format!("/classes/Order/{}", body["objectId"].as_str().unwrap());
+
+        let resp = guest1(&api, "GET", "/classes/Order", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = user1(&api, "foo", "GET", "/classes/Order", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = master1(&api, "GET", "/classes/Order", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = guest1(&api, "GET", "/classes/Order?count=1&limit=0", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = guest1(&api, "GET", "/classes/Order?count=1", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = guest1(&api, "GET", &path, json!({})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Classes reached by subqueries and include are checked as well.
+        let order =
+            json!({"__type": "Pointer", "className": "Order", "objectId": body["objectId"]});
+        let resp = user1(
+            &api,
+            "foo",
+            "POST",
+            "/classes/Invoice",
+            json!({"order": order}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let w = escape(json!({"order": {"$inQuery": {"className": "Order", "where": {}}}}));
+        let invoices = format!("/classes/Invoice?where={}", w);
+        let resp = user1(&api, "foo", "GET", &invoices, json!({})).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let invoices = "/classes/Invoice?include=order";
+        let resp = user1(&api, "foo", "GET", invoices, json!({})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body[0]["order"]["name"], json!("a"));
+
+        // Permissions stored in the schema take precedence.
+        let resp = master1(
+            &api,
+            "PUT",
+            "/schemas/Order",
+            json!({"classLevelPermissions": {
+                "update": {"*": true},
+                "delete": {"foo": true},
+                "addField": {"foo": true, "bar": false},
+            }}),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(
+            body["classLevelPermissions"],
+            json!({
+                "update": {"*": true},
+                "delete": {"foo": true},
+                "addField": {"foo": true},
+            })
+        );
+        let resp = guest1(&api, "GET", "/classes/Order", json!({})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = user1(&api, "bar", "PUT", &path, json!({"name": "b"})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = user1(&api, "bar", "PUT", &path, json!({"price": 1})).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = user1(&api, "foo", "PUT", &path, json!({"price": 1})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = user1(&api, "bar", "PUT", &path, json!({"price": 2})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = user1(&api, "bar", "DELETE", &path, json!({})).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = user1(&api, "foo", "DELETE", &path, json!({})).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Invalid permissions.
+        for p in vec![
+            json!({"write": {"*": true}}),
+            json!({"get": true}),
+            json!({"get": {"*": 1}}),
+        ] {
+            let body = json!({ "classLevelPermissions": p });
+            let resp = master1(&api, "PUT", "/schemas/Order", body).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protected_fields() {
+        let mut s = test_server().await;
+        let p = ProtectedFields::new(vec!["email"]).except_owner("objectId");
+        s.protect_fields("_User", p);
+        let p = ProtectedFields::new(vec!["phone", "note"]).except_owner("owner");
+        s.protect_fields("Contact", p);
+        let api = s.routes().await;
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/users")
+            .json(&json!({"username": "foobar", "password": "12345", "email": "a@b.c"}))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body["email"], json!("a@b.c"));
+        assert_eq!(body["password"], Value::Null);
+        let uid = body["objectId"].as_str().unwrap().to_string();
+
+        let owner = json!({"__type": "Pointer", "className": "_User", "objectId": uid});
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/classes/Contact")
+            .header("x-parse-master-key", TEST_SERVER_KEY)
+            .json(&json!({"name": "a", "phone": "123", "owner": owner}))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body["phone"], json!("123"));
+        let path = format!(
+            "/classes/Contact/{}?include=owner",
+            body["objectId"].as_str().unwrap()
+        );
+
+        // Hidden from others, including fields of objects included.
+        let resp = with_user!("other", "GET").path(&path).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body["name"], json!("a"));
+        assert_eq!(body["phone"], Value::Null);
+        assert_eq!(body["owner"]["username"], json!("foobar"));
+        assert_eq!(body["owner"]["email"], Value::Null);
+        assert_eq!(body["owner"]["password"], Value::Null);
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/classes/Contact")
+            .reply(&api)
+            .await;
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body[0]["name"], json!("a"));
+        assert_eq!(body[0]["phone"], Value::Null);
+
+        // Visible to the owner and Master, except password.
+        let resp = with_user!(uid.as_str(), "GET")
+            .path(&path)
+            .reply(&api)
+            .await;
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body["phone"], json!("123"));
+        assert_eq!(body["owner"]["email"], json!("a@b.c"));
+        assert_eq!(body["owner"]["password"], Value::Null);
+        let resp = warp::test::request()
+            .method("GET")
+            .path(&path)
+            .header("x-parse-master-key", TEST_SERVER_KEY)
+            .reply(&api)
+            .await;
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body["phone"], json!("123"));
+        assert_eq!(body["owner"]["email"], json!("a@b.c"));
+        assert_eq!(body["owner"]["password"], Value::Null);
+
+        // Hidden fields cannot be filtered or sorted by, but by Master.
+        for q in vec![
+            format!("where={}", escape(json!({"phone": "123"}))),
+            "order=-phone".to_string(),
+        ] {
+            let path = format!("/classes/Contact?{}", q);
+            let resp = with_user!("other", "GET").path(&path).reply(&api).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            let resp = warp::test::request()
+                .method("GET")
+                .path(&path)
+                .header("x-parse-master-key", TEST_SERVER_KEY)
+                .reply(&api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        // Subqueries reach no hidden fields nor other built-in classes.
+        let find = |class: &str, w: Value| {
+            let path = format!(
+                "/classes/Contact?where={}",
+                escape(json!({
+                    "owner": {"$inQuery": {"className": class, "where": w}}
+                }))
+            );
+            with_user!("other", "GET").path(&path)
+        };
+        let resp = find("_User", json!({"username": "foobar"}))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        for w in vec![json!({"email": "a@b.c"}), json!({"password": "12345"})] {
+            let resp = find("_User", w).reply(&api).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        }
+        let resp = find("_Session", json!({})).reply(&api).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
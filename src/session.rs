@@ -0,0 +1,598 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use uuid::Uuid;
+use warp::{http::HeaderMap, Rejection, Reply};
+
+use crate::{
+    acl::{Access, Acl},
+    database::{Database, ACL, OBJECT_ID},
+    error::{bad_request, not_found, unauthorized},
+    query::{Constraint, Filter, Order, Query},
+    role,
+    server::{Config, Context, Request},
+    types::{self, Pointer},
+    user::{ClientToken, UserKind},
+};
+
+/// Class of sessions, one object per signing up or logging in, with `tokenId` of the latest
+/// token, pointer `user`, date `expiresAt`, `action` creating it, `installationId` and
+/// `userAgent` of the client, and the hash of the refresh token. Tokens of sessions deleted are
+/// revoked. Sessions are invisible to clients but by the endpoints of sessions.
+pub const SESSION: &'static str = "_Session";
+
+/// Field of the id of the token, i.e. its `jti` claim.
+const TOKEN_ID: &'static str = "tokenId";
+
+/// Field of the date after which the session cannot be refreshed.
+const EXPIRES_AT: &'static str = "expiresAt";
+
+/// Field of the refresh token in bodies of refreshing and responses giving it.
+const REFRESH_TOKEN: &'static str = "refreshToken";
+
+/// Field of the hash of the current refresh token of the session, which is stored instead.
+const REFRESH_TOKEN_HASH: &'static str = "refreshTokenHash";
+
+/// Field of the random prefix shared by all refresh tokens of the session, by which rotated
+/// tokens are recognized.
+const REFRESH_FAMILY: &'static str = "refreshFamily";
+
+/// Lifetime of refresh tokens in seconds if not configured.
+const REFRESH_TOKEN_LIFETIME: i64 = 30 * 24 * 3600;
+
+/// How long validity of a session is cached, after which revocations by other server instances
+/// sharing the database take effect.
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Number of sessions cached, beyond which expired entries are removed, or all entries if none
+/// has expired.
+const CACHE_SIZE: usize = 10000;
+
+/// Cache `v` of `id` in `map`, keeping at most `CACHE_SIZE` entries.
+fn cache_insert<T>(map: &mut HashMap<String, (T, Instant)>, id: &str, v: T) {
+    if map.len() >= CACHE_SIZE {
+        map.retain(|_, (_, t)| t.elapsed() < CACHE_TTL);
+    }
+    if map.len() >= CACHE_SIZE {
+        map.clear();
+    }
+    map.insert(id.to_string(), (v, Instant::now()));
+}
+
+/// Cache of validity of sessions by token id, and names of roles by user id, so that every
+/// request does not hit the database.
+#[derive(Default)]
+pub(crate) struct SessionCache {
+    entries: Mutex<HashMap<String, (bool, Instant)>>,
+    roles: Mutex<HashMap<String, (Vec<String>, Instant)>>,
+}
+
+impl SessionCache {
+    fn get(&self, id: &str) -> Option<bool> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(id) {
+            Some((valid, t)) if t.elapsed() < CACHE_TTL => Some(*valid),
+            _ => None,
+        }
+    }
+
+    fn set(&self, id: &str, valid: bool) {
+        cache_insert(&mut self.entries.lock().unwrap(), id, valid);
+    }
+
+    /// Check if the session of token id `id` is not revoked.
+    async fn valid(&self, id: &str, db: &dyn Database) -> Result<bool, Rejection> {
+        if let Some(valid) = self.get(id) {
+            return Ok(valid);
+        }
+        let filter = Filter::Field(TOKEN_ID.to_string(), Constraint::Equal(id.into()));
+        let valid = db.count(SESSION, filter, UserKind::Master).await? > 0;
+        self.set(id, valid);
+        Ok(valid)
+    }
+
+    fn cached_roles(&self, id: &str) -> Option<Vec<String>> {
+        let roles = self.roles.lock().unwrap();
+        match roles.get(id) {
+            Some((names, t)) if t.elapsed() < CACHE_TTL => Some(names.clone()),
+            _ => None,
+        }
+    }
+
+    /// Names of roles of user `id`, see `role::resolve`.
+    pub(crate) async fn roles(
+        &self,
+        id: &str,
+        db: &dyn Database,
+    ) -> Result<Vec<String>, Rejection> {
+        if let Some(names) = self.cached_roles(id) {
+            return Ok(names);
+        }
+        let names = role::resolve(db, id).await?;
+        cache_insert(&mut self.roles.lock().unwrap(), id, names.clone());
+        Ok(names)
+    }
+
+    /// Drop all roles cached once roles are modified by this server.
+    pub(crate) fn clear_roles(&self) {
+        self.roles.lock().unwrap().clear();
+    }
+
+    /// Reject `user` whose session is revoked, while tokens without `jti` issued by previous
+    /// versions are accepted until they expire.
+    pub(crate) async fn check(
+        &self,
+        user: UserKind,
+        db: &dyn Database,
+    ) -> Result<UserKind, Rejection> {
+        if let UserKind::Client(t) = &user {
+            if !t.jti.is_empty() && !self.valid(&t.jti, db).await? {
+                return unauthorized("Session revoked");
+            }
+        }
+        Ok(user)
+    }
+}
+
+/// Expiry of sessions started now, after `Config::refresh_token_lifetime`.
+fn expiry(config: &Config) -> DateTime<Utc> {
+    let lifetime = match config.refresh_token_lifetime {
+        0 => REFRESH_TOKEN_LIFETIME,
+        n => n as i64,
+    };
+    Utc::now() + ChronoDuration::seconds(lifetime)
+}
+
+/// New refresh token of the session of `family`.
+fn refresh_token(family: &str) -> String {
+    format!("{}:{}", family, Uuid::new_v4().to_simple())
+}
+
+/// Hash of refresh token `t` by SHA-256, so that those who read sessions cannot use them.
+fn hash(t: &str) -> String {
+    base64::encode(ring::digest::digest(&ring::digest::SHA256, t.as_bytes()))
+}
+
+/// Start a session of `token` created by `action` such as `login`, setting its `jti`.
+///
+/// Return the refresh token of the session.
+pub(crate) async fn start(
+    ctx: &Context,
+    token: &mut ClientToken,
+    headers: &HeaderMap,
+    action: &str,
+) -> Result<String, Rejection> {
+    token.jti = Uuid::new_v4().to_string();
+    let family = Uuid::new_v4().to_simple().to_string();
+    let refresh = refresh_token(&family);
+    let header = |k: &str| headers.get(k).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let mut acl = Acl::new();
+    acl.set_public_access(Access::NONE);
+    let d = doc! {
+        TOKEN_ID: &token.jti,
+        "user": Pointer::new("_User", &token.id),
+        EXPIRES_AT: expiry(&ctx.config),
+        "action": action,
+        "installationId": header("x-parse-installation-id"),
+        "userAgent": header("user-agent"),
+        REFRESH_FAMILY: family,
+        REFRESH_TOKEN_HASH: hash(&refresh),
+        ACL: Document::from(acl),
+    };
+    ctx.db.create(SESSION, d, UserKind::Master).await?;
+    ctx.sessions.set(&token.jti, true);
+    Ok(refresh)
+}
+
+/// Session `d` without the hash and family of its refresh token, which is only given to the
+/// client on starting and refreshing the session.
+pub(crate) fn hide(mut d: Document) -> Document {
+    // Stored in plaintext by previous versions.
+    d.remove(REFRESH_TOKEN);
+    d.remove(REFRESH_TOKEN_HASH);
+    d.remove(REFRESH_FAMILY);
+    d
+}
+
+/// Session of the token of `user`.
+async fn current(ctx: &Context, user: &UserKind) -> Result<Document, Rejection> {
+    let id = match user {
+        UserKind::Client(t) if !t.jti.is_empty() => &t.jti,
+        UserKind::Client(_) => return not_found("Session not found"),
+        _ => return unauthorized("Not logged in"),
+    };
+    let filter = Filter::Field(TOKEN_ID.to_string(), Constraint::Equal(id.into()));
+    let v = ctx
+        .db
+        .retrieve(SESSION, filter.into(), UserKind::Master)
+        .await?;
+    match v.into_iter().next() {
+        Some(d) => Ok(d),
+        None => not_found("Session not found"),
+    }
+}
+
+/// Delete session `d` and revoke its token.
+async fn revoke(ctx: &Context, d: &Document) -> Result<Document, Rejection> {
+    let id = d.get_str(OBJECT_ID).unwrap_or_default();
+    let d = ctx.db.delete(SESSION, id, UserKind::Master).await?;
+    if let Ok(token_id) = d.get_str(TOKEN_ID) {
+        ctx.sessions.set(token_id, false);
+    }
+    Ok(hide(d))
+}
+
+/// Revoke sessions of user `id` but the one `user` is requesting by, e.g. once its password
+/// changes, so that stolen sessions do not survive resetting the password.
+pub(crate) async fn revoke_others(
+    ctx: &Context,
+    id: &str,
+    user: &UserKind,
+) -> Result<(), Rejection> {
+    let current = match user {
+        UserKind::Client(t) if t.id == id && !t.jti.is_empty() => Some(t.jti.as_str()),
+        _ => None,
+    };
+    let pointer = Pointer::new("_User", id).into();
+    let filter = Filter::Field("user".to_string(), Constraint::Equal(pointer));
+    let v = ctx
+        .db
+        .retrieve(SESSION, filter.into(), UserKind::Master)
+        .await?;
+    for d in v.iter().filter(|d| d.get_str(TOKEN_ID).ok() != current) {
+        revoke(ctx, d).await?;
+    }
+    Ok(())
+}
+
+/// Exchange the refresh token in body `{"refreshToken": ...}` for a new session token and
+/// refresh token, used by RESTFul API.
+///
+/// The old token and refresh token are revoked. Since only the latest refresh token is valid,
+/// reusing a rotated one means it has been stolen, and the whole session is revoked, even if
+/// both are used at the same time, as the refresh token is replaced only if still current.
+pub async fn refresh(req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejection> {
+    let old = match req.body.as_ref().map(|b| b.get_str(REFRESH_TOKEN)) {
+        Some(Ok(t)) => t.to_string(),
+        _ => return bad_request("Refresh token required"),
+    };
+    let family = old.split(':').next().unwrap_or_default();
+    let filter = Filter::Field(REFRESH_FAMILY.to_string(), Constraint::Equal(family.into()));
+    let v = ctx
+        .db
+        .retrieve(SESSION, filter.into(), UserKind::Master)
+        .await?;
+    let d = match v.into_iter().next() {
+        Some(d) => d,
+        None => return unauthorized("Invalid refresh token"),
+    };
+    if d.get_str(REFRESH_TOKEN_HASH).ok() != Some(hash(&old).as_str()) {
+        warn!(
+            "rotated refresh token reused, revoke session {:?}",
+            d.get(OBJECT_ID)
+        );
+        revoke(&ctx, &d).await?;
+        return unauthorized("Refresh token reused, session revoked");
+    }
+    match d.get_datetime(EXPIRES_AT) {
+        Ok(t) if *t > Utc::now() => {}
+        _ => return unauthorized("Session expired"),
+    }
+
+    let id = d.get_str(OBJECT_ID).unwrap_or_default();
+    let user_id = d
+        .get_document("user")
+        .ok()
+        .and_then(Pointer::from_doc)
+        .map(|p| p.object_id)
+        .unwrap_or_default();
+    let filter = doc! {OBJECT_ID: &user_id};
+    let v = ctx
+        .db
+        .retrieve("_User", filter.into(), UserKind::Master)
+        .await?;
+    let name = match v.first().map(|u| u.get_str("username")) {
+        Some(Ok(name)) => name.to_string(),
+        _ => {
+            revoke(&ctx, &d).await?;
+            return unauthorized("User not found");
+        }
+    };
+
+    let mut token = ClientToken::new(&ctx.config, user_id, name);
+    token.jti = Uuid::new_v4().to_string();
+    let refresh = refresh_token(family);
+    let update = doc! {TOKEN_ID: &token.jti, REFRESH_TOKEN_HASH: hash(&refresh)};
+    let current = Bson::String(hash(&old));
+    if !ctx
+        .db
+        .update_if(SESSION, id, REFRESH_TOKEN_HASH, current, update)
+        .await?
+    {
+        // Rotated meanwhile by another request with the same refresh token.
+        warn!("refresh token reused concurrently, revoke session {:?}", id);
+        revoke(&ctx, &d).await.ok();
+        return unauthorized("Refresh token reused, session revoked");
+    }
+    if let Ok(token_id) = d.get_str(TOKEN_ID) {
+        ctx.sessions.set(token_id, false);
+    }
+    ctx.sessions.set(&token.jti, true);
+
+    let session_token = ctx.keys.encode(&token)?;
+    types::to_json(doc! {"sessionToken": session_token, REFRESH_TOKEN: refresh})
+}
+
+/// Log out by revoking the session of the requesting user, used by RESTFul API.
+pub async fn logout(req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejection> {
+    let d = current(&ctx, &req.user).await?;
+    revoke(&ctx, &d).await?;
+    Ok("{}".to_string())
+}
+
+/// Retrieve the session of the requesting user, used by RESTFul API.
+pub async fn me(req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejection> {
+    types::to_json(hide(current(&ctx, &req.user).await?))
+}
+
+fn master_only(user: &UserKind) -> Result<(), Rejection> {
+    match user {
+        UserKind::Master => Ok(()),
+        _ => unauthorized("Only Master is allowed to manage sessions"),
+    }
+}
+
+/// List sessions as `{"results": [...]}` by query string such as `where` and `limit`, newest
+/// first by default, used by RESTFul API.
+pub async fn list(
+    q: HashMap<String, String>,
+    req: Request,
+    ctx: Arc<Context>,
+) -> Result<impl Reply, Rejection> {
+    master_only(&req.user)?;
+    let mut query = Query::from_query(q)?;
+    if query.order.is_empty() {
+        query = query.order_by("createdAt", Order::Descending);
+    }
+    let results = ctx.db.retrieve(SESSION, query, UserKind::Master).await?;
+    let results: Vec<Document> = results.into_iter().map(hide).collect();
+    types::to_json(doc! {"results": results})
+}
+
+/// Delete session `id` and revoke its token, used by RESTFul API.
+pub async fn delete(id: String, req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejection> {
+    master_only(&req.user)?;
+    let filter = doc! {OBJECT_ID: id};
+    let v = ctx
+        .db
+        .retrieve(SESSION, filter.into(), UserKind::Master)
+        .await?;
+    match v.first() {
+        Some(d) => types::to_json(revoke(&ctx, d).await?),
+        None => not_found("Session not found"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value};
+    use warp::hyper::StatusCode;
+
+    use super::super::tests::{test_api, TEST_SERVER_KEY};
+    use crate::{login1, signup1, with_user};
+
+    use super::{SessionCache, CACHE_SIZE};
+
+    #[test]
+    fn test_cache_size() {
+        let cache = SessionCache::default();
+        for i in 0..CACHE_SIZE + 1 {
+            cache.set(&i.to_string(), true);
+        }
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+        assert_eq!(cache.get(&CACHE_SIZE.to_string()), Some(true));
+        assert_eq!(cache.get("0"), None);
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let api = test_api().await;
+        let request1 = async move |api, token: &str, method, path| {
+            let resp = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("x-parse-session-token", token)
+                .reply(api)
+                .await;
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            (resp.status(), body)
+        };
+        let master1 = async move |api, method, path| {
+            let resp = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("x-parse-master-key", TEST_SERVER_KEY)
+                .reply(api)
+                .await;
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            (resp.status(), body)
+        };
+        let token_of = |body: &[u8]| {
+            let body: Value = serde_json::from_slice(body).unwrap();
+            body["sessionToken"].as_str().unwrap().to_string()
+        };
+
+        let resp = signup1!(&api, "foobar", "12345");
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let t1 = token_of(&resp.body()[..]);
+        let resp = login1!(&api, "foobar", "12345");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let t2 = token_of(&resp.body()[..]);
+
+        let (status, body) = request1(&api, &t1, "GET", "/sessions/me").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["action"], json!("signup"));
+        let user = body["user"]["objectId"].clone();
+        let (status, body) = master1(&api, "GET", "/sessions").await;
+        assert_eq!(status, StatusCode::OK);
+        let sessions = body["results"].as_array().unwrap();
+        let mut actions: Vec<&str> = sessions
+            .iter()
+            .map(|s| s["action"].as_str().unwrap())
+            .collect();
+        actions.sort();
+        assert_eq!(actions, vec!["login", "signup"]);
+        assert!(sessions.iter().all(|s| s["user"]["objectId"] == user));
+        let (status, _) = request1(&api, &t1, "GET", "/sessions").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Logged out.
+        let (status, _) = request1(&api, &t1, "POST", "/logout").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request1(&api, &t1, "GET", "/sessions/me").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = request1(&api, &t2, "GET", "/sessions/me").await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Revoked by Master.
+        let (_, body) = master1(&api, "GET", "/sessions").await;
+        let id = body["results"][0]["objectId"].as_str().unwrap().to_string();
+        let path = format!("/sessions/{}", id);
+        let (status, _) = master1(&api, "DELETE", path.as_str()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request1(&api, &t2, "GET", "/classes/foo").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = master1(&api, "DELETE", path.as_str()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Tokens without sessions.
+        let resp = with_user!("a", "GET")
+            .path("/sessions/me")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let api = test_api().await;
+        let refresh1 = async move |api, refresh: &str| {
+            let resp = warp::test::request()
+                .method("POST")
+                .path("/users/refresh")
+                .json(&json!({ "refreshToken": refresh }))
+                .reply(api)
+                .await;
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            (resp.status(), body)
+        };
+        let me1 = async move |api, token: &Value| {
+            warp::test::request()
+                .method("GET")
+                .path("/sessions/me")
+                .header("x-parse-session-token", token.as_str().unwrap())
+                .reply(api)
+                .await
+                .status()
+        };
+
+        let resp = signup1!(&api, "foobar", "12345");
+        let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
+        let (t1, r1) = (body["sessionToken"].clone(), body["refreshToken"].clone());
+        let resp = login1!(&api, "foobar", "12345");
+        let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
+        let t2 = body["sessionToken"].clone();
+
+        // Rotated.
+        let (status, body) = refresh1(&api, r1.as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (t3, r3) = (body["sessionToken"].clone(), body["refreshToken"].clone());
+        assert_ne!(r1, r3);
+        assert_eq!(me1(&api, &t1).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(me1(&api, &t3).await, StatusCode::OK);
+        let (status, _) = refresh1(&api, "foo:bar").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Reused, revoking the session but not others.
+        let (status, _) = refresh1(&api, r1.as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(me1(&api, &t3).await, StatusCode::UNAUTHORIZED);
+        let (status, _) = refresh1(&api, r3.as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(me1(&api, &t2).await, StatusCode::OK);
+
+        // Refresh tokens are stored as hashes, and never returned.
+        let resp = warp::test::request()
+            .path("/sessions")
+            .header("x-parse-master-key", TEST_SERVER_KEY)
+            .reply(&api)
+            .await;
+        let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
+        let sessions = body["results"].as_array().unwrap();
+        assert!(!sessions.is_empty());
+        for s in sessions {
+            for k in ["refreshToken", "refreshTokenHash", "refreshFamily"].iter() {
+                assert_eq!(s[k], Value::Null);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_password_change() {
+        let api = test_api().await;
+        let update1 = async move |api, token: &Value, id: &Value, pwd| {
+            let mut req = warp::test::request()
+                .method("POST")
+                .path(&format!("/users/{}", id.as_str().unwrap()))
+                .json(&json!({ "password": pwd }));
+            req = match token.as_str() {
+                Some(t) => req.header("x-parse-session-token", t),
+                None => req.header("x-parse-master-key", TEST_SERVER_KEY),
+            };
+            req.reply(api).await.status()
+        };
+        let me1 = async move |api, token: &Value| {
+            warp::test::request()
+                .method("GET")
+                .path("/sessions/me")
+                .header("x-parse-session-token", token.as_str().unwrap())
+                .reply(api)
+                .await
+                .status()
+        };
+
+        let resp = signup1!(&api, "foobar", "12345");
+        let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
+        let (t1, r1) = (body["sessionToken"].clone(), body["refreshToken"].clone());
+        let id = body["objectId"].clone();
+        let resp = login1!(&api, "foobar", "12345");
+        let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
+        let t2 = body["sessionToken"].clone();
+        let resp = signup1!(&api, "other", "12345");
+        let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
+        let t3 = body["sessionToken"].clone();
+
+        // Other sessions of the user are revoked, with their refresh tokens.
+        assert_eq!(update1(&api, &t2, &id, "123456").await, StatusCode::OK);
+        assert_eq!(me1(&api, &t1).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(me1(&api, &t2).await, StatusCode::OK);
+        assert_eq!(me1(&api, &t3).await, StatusCode::OK);
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/users/refresh")
+            .json(&json!({ "refreshToken": r1 }))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // All of them if changed by Master.
+        assert_eq!(update1(&api, &Value::Null, &id, "1234567").await, StatusCode::OK);
+        assert_eq!(me1(&api, &t2).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(me1(&api, &t3).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,416 @@
+use chrono::{SecondsFormat, TimeZone, Utc};
+use mongodb::bson::{doc, spec::BinarySubtype, Binary, Bson, Document};
+use warp::Rejection;
+
+use crate::error::{bad_request, internal_server_error};
+
+/// Key of the type name in documents of special types, e.g. `{"__type": "Pointer", ...}`.
+pub const TYPE: &'static str = "__type";
+
+/// Whether `name` is valid as a class name, including those of built-in classes such as `_User`.
+pub(crate) fn class_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Reference to an object, encoded as
+/// `{"__type": "Pointer", "className": CLASS, "objectId": ID}`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pointer {
+    /// Class of the object referenced.
+    pub class_name: String,
+    /// Id of the object referenced.
+    pub object_id: String,
+}
+
+impl Pointer {
+    /// Pointer to object of `object_id` in class `class_name`.
+    pub fn new(class_name: impl Into<String>, object_id: impl Into<String>) -> Self {
+        Self {
+            class_name: class_name.into(),
+            object_id: object_id.into(),
+        }
+    }
+
+    /// Parse the pointer encoded in `doc`, which is `None` if it is not a valid one.
+    pub fn from_doc(doc: &Document) -> Option<Self> {
+        match (
+            doc.get_str(TYPE),
+            doc.get_str("className"),
+            doc.get_str("objectId"),
+        ) {
+            (Ok("Pointer"), Ok(c), Ok(id)) if class_name(c) && !id.is_empty() => {
+                Some(Self::new(c, id))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse the pointer encoded in `v`, which is `None` if it is not a valid one.
+    pub fn from_bson(v: &Bson) -> Option<Self> {
+        v.as_document().and_then(Self::from_doc)
+    }
+}
+
+impl From<Pointer> for Document {
+    fn from(p: Pointer) -> Self {
+        doc! {TYPE: "Pointer", "className": p.class_name, "objectId": p.object_id}
+    }
+}
+
+impl From<Pointer> for Bson {
+    fn from(p: Pointer) -> Self {
+        Bson::Document(p.into())
+    }
+}
+
+/// Many-to-many reference to objects in a class, encoded as
+/// `{"__type": "Relation", "className": CLASS, "objectIds": [ID, ...]}`.
+///
+/// It is updated by operations `AddRelation` and `RemoveRelation`, and the objects are
+/// queried by `$relatedTo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relation {
+    /// Class of the objects referenced.
+    pub class_name: String,
+    /// Ids of the objects referenced.
+    pub object_ids: Vec<String>,
+}
+
+impl Relation {
+    /// Empty relation to objects in class `class_name`.
+    pub fn new(class_name: impl Into<String>) -> Self {
+        Self {
+            class_name: class_name.into(),
+            object_ids: vec![],
+        }
+    }
+
+    /// Parse the relation encoded in `doc`, where missing `objectIds` is taken as empty.
+    pub fn from_doc(doc: &Document) -> Option<Self> {
+        let ids = match doc.get("objectIds") {
+            None => vec![],
+            Some(Bson::Array(a)) => a
+                .iter()
+                .map(|x| x.as_str().map(String::from))
+                .collect::<Option<Vec<String>>>()?,
+            Some(_) => return None,
+        };
+        match (doc.get_str(TYPE), doc.get_str("className")) {
+            (Ok("Relation"), Ok(c)) if class_name(c) => Some(Self {
+                class_name: c.to_string(),
+                object_ids: ids,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl From<Relation> for Bson {
+    fn from(r: Relation) -> Self {
+        Bson::Document(doc! {
+            TYPE: "Relation",
+            "className": r.class_name,
+            "objectIds": r.object_ids,
+        })
+    }
+}
+
+/// Decode `{"__type": "Date", "iso": ISO}` into datetime of milliseconds.
+fn date(d: &Document) -> Option<Bson> {
+    let t = chrono::DateTime::parse_from_rfc3339(d.get_str("iso").ok()?).ok()?;
+    Some(Bson::DateTime(Utc.timestamp_millis(t.timestamp_millis())))
+}
+
+/// Decode `{"__type": "Bytes", "base64": BASE64}` into binary.
+fn bytes(d: &Document) -> Option<Bson> {
+    let bytes = base64::decode(d.get_str("base64").ok()?).ok()?;
+    Some(Bson::Binary(Binary {
+        subtype: BinarySubtype::Generic,
+        bytes,
+    }))
+}
+
+fn number(v: &Bson) -> Option<f64> {
+    match v {
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        Bson::Double(f) => Some(*f),
+        _ => None,
+    }
+}
+
+/// Radius of the earth in kilometers, the same as the one used by MongoDB.
+pub const EARTH_RADIUS: f64 = 6378.1;
+
+/// Point on the earth, encoded as `{"__type": "GeoPoint", "latitude": LAT, "longitude": LNG}`
+/// and stored as GeoJSON point `{"type": "Point", "coordinates": [LNG, LAT], "__type": "GeoPoint"}`,
+/// as required by geospatial indexes, which ignore the tag. Untagged GeoJSON is an ordinary object.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    /// Latitude in degrees, between -90 and 90.
+    pub latitude: f64,
+    /// Longitude in degrees, between -180 and 180.
+    pub longitude: f64,
+}
+
+impl GeoPoint {
+    /// Point of `latitude` and `longitude` in degrees, which is `None` if out of range.
+    pub fn new(latitude: f64, longitude: f64) -> Option<Self> {
+        if latitude >= -90.0 && latitude <= 90.0 && longitude >= -180.0 && longitude <= 180.0 {
+            Some(Self {
+                latitude,
+                longitude,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Parse the point encoded in `doc`, which is `None` if it is not a valid one.
+    pub fn from_doc(doc: &Document) -> Option<Self> {
+        match doc.get_str(TYPE) {
+            Ok("GeoPoint") => Self::new(
+                number(doc.get("latitude")?)?,
+                number(doc.get("longitude")?)?,
+            ),
+            _ => None,
+        }
+    }
+
+    fn from_geojson(d: &Document) -> Option<Self> {
+        match (
+            d.len(),
+            d.get_str(TYPE),
+            d.get_str("type"),
+            d.get_array("coordinates"),
+        ) {
+            (3, Ok("GeoPoint"), Ok("Point"), Ok(c)) if c.len() == 2 => {
+                Self::new(number(&c[1])?, number(&c[0])?)
+            }
+            _ => None,
+        }
+    }
+
+    /// GeoJSON point without the tag, as used in geospatial queries.
+    pub(crate) fn to_geojson(&self) -> Document {
+        doc! {"type": "Point", "coordinates": [self.longitude, self.latitude]}
+    }
+
+    /// Parse the point stored as GeoJSON in `v`, which is `None` if it is not a valid one.
+    pub fn from_bson(v: &Bson) -> Option<Self> {
+        v.as_document().and_then(Self::from_geojson)
+    }
+
+    /// Great-circle distance to `other` in kilometers.
+    pub fn distance(&self, other: &Self) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlng = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlng / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+    }
+
+    /// Whether it is within the box of `southwest` and `northeast` corners, inclusively.
+    pub fn within_box(&self, southwest: &Self, northeast: &Self) -> bool {
+        self.latitude >= southwest.latitude
+            && self.latitude <= northeast.latitude
+            && self.longitude >= southwest.longitude
+            && self.longitude <= northeast.longitude
+    }
+
+    /// Whether it is within the polygon of `vertices`, taking latitude and longitude as
+    /// planar coordinates.
+    pub fn within_polygon(&self, vertices: &[Self]) -> bool {
+        let (x, y) = (self.longitude, self.latitude);
+        let mut inside = false;
+        for (i, a) in vertices.iter().enumerate() {
+            let b = &vertices[(i + 1) % vertices.len()];
+            if (a.latitude > y) != (b.latitude > y)
+                && x < (b.longitude - a.longitude) * (y - a.latitude) / (b.latitude - a.latitude)
+                    + a.longitude
+            {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+}
+
+impl From<GeoPoint> for Bson {
+    fn from(p: GeoPoint) -> Self {
+        let mut d = p.to_geojson();
+        d.insert(TYPE, "GeoPoint");
+        Bson::Document(d)
+    }
+}
+
+/// Validate `{"__type": "File", "name": NAME, "url": URL}` where `url` is optional.
+fn file(d: &Document) -> Option<Bson> {
+    let mut file = doc! {TYPE: "File", "name": d.get_str("name").ok()?};
+    match d.get("url") {
+        None => {}
+        Some(Bson::String(url)) => {
+            file.insert("url", url.clone());
+        }
+        Some(_) => return None,
+    }
+    Some(Bson::Document(file))
+}
+
+/// Decode values of special types in `v` recursively into the form stored, i.e.
+///
+/// - `Date` into datetime,
+/// - `Bytes` into binary,
+/// - `GeoPoint` into GeoJSON point,
+/// - `Pointer`, `Relation` and `File` into the canonical form, so that they compare equal
+///   as long as they are the same.
+///
+/// Values already decoded are kept as is.
+pub(crate) fn decode(v: Bson) -> Result<Bson, Rejection> {
+    Ok(match v {
+        Bson::Document(d) => match d.get(TYPE) {
+            Some(Bson::String(t)) => {
+                let v = match t.as_str() {
+                    "Date" => date(&d),
+                    "Bytes" => bytes(&d),
+                    "GeoPoint" => GeoPoint::from_doc(&d).map(Bson::from),
+                    "File" => file(&d),
+                    "Pointer" => Pointer::from_doc(&d).map(Bson::from),
+                    "Relation" => Relation::from_doc(&d).map(Bson::from),
+                    _ => return bad_request(format!("Unknown type {}", t)),
+                };
+                match v {
+                    Some(v) => v,
+                    None => return bad_request(format!("Invalid value of type {}", t)),
+                }
+            }
+            Some(t) => return bad_request(format!("Unknown type {}", t)),
+            None => Bson::Document(decode_doc(d)?),
+        },
+        Bson::Array(a) => Bson::Array(a.into_iter().map(decode).collect::<Result<_, _>>()?),
+        v => v,
+    })
+}
+
+/// Decode values of special types in fields of `d`, see `decode`.
+pub(crate) fn decode_doc(d: Document) -> Result<Document, Rejection> {
+    d.into_iter()
+        .map(|(k, v)| decode(v).map(|v| (k, v)))
+        .collect()
+}
+
+/// Encode values of special types in `v` recursively for clients, the reverse of `decode`.
+pub fn encode(v: Bson) -> Bson {
+    match v {
+        Bson::DateTime(t) => Bson::Document(doc! {
+            TYPE: "Date",
+            "iso": t.to_rfc3339_opts(SecondsFormat::Millis, true),
+        }),
+        Bson::Binary(b) => Bson::Document(doc! {TYPE: "Bytes", "base64": base64::encode(&b.bytes)}),
+        Bson::Document(d) => match GeoPoint::from_geojson(&d) {
+            Some(p) => Bson::Document(doc! {
+                TYPE: "GeoPoint",
+                "latitude": p.latitude,
+                "longitude": p.longitude,
+            }),
+            None => Bson::Document(d.into_iter().map(|(k, v)| (k, encode(v))).collect()),
+        },
+        Bson::Array(a) => Bson::Array(a.into_iter().map(encode).collect()),
+        v => v,
+    }
+}
+
+/// Serialize `v` into JSON for clients, with values of special types encoded.
+pub(crate) fn to_json(v: impl Into<Bson>) -> Result<String, Rejection> {
+    serde_json::to_string(&encode(v.into()))
+        .map_or_else(|_e| internal_server_error("Serialization error"), Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let p = doc! {"objectId": "x", "className": "_User", TYPE: "Pointer"};
+        let v = decode(Bson::Document(doc! {"a": [p], "b": 1})).unwrap();
+        assert_eq!(
+            v,
+            Bson::Document(doc! {"a": [Pointer::new("_User", "x")], "b": 1})
+        );
+        let v = decode(Bson::Document(doc! {TYPE: "Relation", "className": "foo"})).unwrap();
+        assert_eq!(
+            Relation::from_doc(v.as_document().unwrap()),
+            Some(Relation::new("foo"))
+        );
+
+        assert!(decode(Bson::Document(doc! {TYPE: "Pointer", "className": "foo"})).is_err());
+        assert!(decode(Bson::Document(
+            doc! {TYPE: "Pointer", "className": "a.b", "objectId": "x"}
+        ))
+        .is_err());
+        assert!(decode(Bson::Document(doc! {TYPE: "Relation", "objectIds": []})).is_err());
+        assert!(decode(Bson::Document(doc! {TYPE: "Unknown"})).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let values = vec![
+            doc! {TYPE: "Date", "iso": "2020-12-01T08:00:00.123Z"},
+            doc! {TYPE: "Bytes", "base64": "aGVsbG8="},
+            doc! {TYPE: "GeoPoint", "latitude": 30.5, "longitude": -120.0},
+            doc! {TYPE: "File", "name": "a.txt", "url": "http://localhost/a.txt"},
+            Pointer::new("foo", "x").into(),
+        ];
+        for v in values {
+            let decoded = decode(Bson::Document(v.clone())).unwrap();
+            assert_eq!(decode(decoded.clone()).unwrap(), decoded);
+            assert_eq!(encode(decoded), Bson::Document(v));
+        }
+        let v = decode(Bson::Document(
+            doc! {TYPE: "Date", "iso": "2020-12-01T16:00:00+08:00"},
+        ));
+        assert_eq!(
+            encode(v.unwrap()),
+            Bson::Document(doc! {TYPE: "Date", "iso": "2020-12-01T08:00:00.000Z"})
+        );
+
+        assert!(decode(Bson::Document(doc! {TYPE: "Date", "iso": "yesterday"})).is_err());
+        assert!(decode(Bson::Document(doc! {TYPE: "Bytes", "base64": "!"})).is_err());
+        assert!(decode(Bson::Document(
+            doc! {TYPE: "GeoPoint", "latitude": 91, "longitude": 0}
+        ))
+        .is_err());
+        assert!(decode(Bson::Document(doc! {TYPE: "File", "url": "x"})).is_err());
+    }
+
+    #[test]
+    fn test_geo_point() {
+        let p = |lat, lng| GeoPoint::new(lat, lng).unwrap();
+        assert!(GeoPoint::new(90.5, 0.0).is_none());
+        assert!(GeoPoint::new(0.0, -181.0).is_none());
+        assert_eq!(
+            GeoPoint::from_bson(&p(10.0, 20.0).into()),
+            Some(p(10.0, 20.0))
+        );
+        // GeoJSON from clients is kept as is.
+        let geojson = Bson::Document(p(10.0, 20.0).to_geojson());
+        assert_eq!(GeoPoint::from_bson(&geojson), None);
+        assert_eq!(encode(geojson.clone()), geojson);
+
+        // One degree of latitude is about 111 kilometers.
+        let d = p(30.0, 120.0).distance(&p(31.0, 120.0));
+        assert!((d - 111.3).abs() < 0.1);
+        let d = p(-90.0, 0.0).distance(&p(90.0, 0.0));
+        assert!((d - std::f64::consts::PI * EARTH_RADIUS).abs() < 1e-6);
+
+        assert!(p(1.0, 1.0).within_box(&p(0.0, 0.0), &p(1.0, 2.0)));
+        assert!(!p(1.0, 3.0).within_box(&p(0.0, 0.0), &p(1.0, 2.0)));
+        let triangle = vec![p(0.0, 0.0), p(0.0, 2.0), p(2.0, 1.0)];
+        assert!(p(1.0, 1.0).within_polygon(&triangle));
+        assert!(!p(1.5, 0.2).within_polygon(&triangle));
+    }
+}
@@ -1,14 +1,18 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use crate::{
     database::{self, Database},
-    error::{self, conflict, internal_server_error, unauthorized},
-    object::ObjectTrait,
-    server::{Context, Request},
+    error::{self, conflict, internal_server_error, not_found, unauthorized},
+    object::{self, ObjectTrait},
+    query,
+    schema::{self, ClassOperation},
+    server::{Config, Context, Request},
+    session, types,
     validator::{UserName, UserPassword},
     Acl,
 };
 use error::bad_request;
+use lazy_static::lazy_static;
 use mongodb::bson::{doc, Bson, Document};
 use serde::{Deserialize, Serialize};
 use warp::{Rejection, Reply};
@@ -26,6 +30,97 @@ pub struct ClientToken {
 
     /// Verified name of this user.
     pub name: String,
+
+    /// Names of roles of this user, resolved on each request rather than kept in the token.
+    #[serde(skip)]
+    pub(crate) roles: Vec<String>,
+
+    /// Id of the session of this token in `_Session`, empty for tokens issued before sessions.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) jti: String,
+}
+
+/// Lifetime of session tokens in seconds if not configured.
+const TOKEN_LIFETIME: i64 = 900;
+
+impl ClientToken {
+    /// Token of user `id` named `name`, expiring after `Config::token_lifetime`.
+    pub(crate) fn new(config: &Config, id: String, name: String) -> Self {
+        let lifetime = match config.token_lifetime {
+            0 => TOKEN_LIFETIME,
+            n => n as i64,
+        };
+        ClientToken {
+            sub: id.clone(),
+            exp: chrono::Utc::now().timestamp() + lifetime,
+            id,
+            name,
+            roles: vec![],
+            jti: String::new(),
+        }
+    }
+
+    /// Names of roles of this user, including those of parent roles.
+    pub fn roles(&self) -> &[String] {
+        &self.roles
+    }
+}
+
+/// Cost of hashing passwords by bcrypt, lowered in tests to keep them fast.
+#[cfg(not(test))]
+const HASH_COST: u32 = bcrypt::DEFAULT_COST;
+#[cfg(test)]
+const HASH_COST: u32 = 4;
+
+/// Hash password `pwd` by bcrypt on a blocking thread, since it is slow by design.
+async fn hash_password(pwd: String) -> Result<String, Rejection> {
+    match tokio::task::spawn_blocking(move || bcrypt::hash(pwd, HASH_COST)).await {
+        Ok(Ok(h)) => Ok(h),
+        _ => internal_server_error("Failed to hash password"),
+    }
+}
+
+/// Check if password `pwd` matches `stored`, which is either a bcrypt hash or the plaintext
+/// of legacy records, compared in constant time. Legacy ones starting with `$` are taken as
+/// hashes, which never match since they cannot be parsed as such.
+async fn verify_password(pwd: String, stored: String) -> Result<bool, Rejection> {
+    if !legacy_password(&stored) {
+        match tokio::task::spawn_blocking(move || bcrypt::verify(pwd, &stored)).await {
+            Ok(Ok(matched)) => Ok(matched),
+            Ok(Err(_)) => Ok(false),
+            Err(_) => internal_server_error("Failed to verify password"),
+        }
+    } else {
+        let (pwd, stored) = (pwd.as_bytes(), stored.as_bytes());
+        Ok(ring::constant_time::verify_slices_are_equal(pwd, stored).is_ok())
+    }
+}
+
+/// Check if password `stored` is plaintext rather than a bcrypt hash.
+fn legacy_password(stored: &str) -> bool {
+    !stored.starts_with('$')
+}
+
+lazy_static! {
+    /// Hash verified against when logging in as unknown users, taking as long as known ones.
+    static ref DUMMY_HASH: String = bcrypt::hash("dummy-password", HASH_COST).unwrap();
+}
+
+/// Replace password in `data` of a user to save by its hash, rejecting invalid ones, and
+/// return whether it is given.
+pub(crate) async fn hash_password_of(data: &mut Document) -> Result<bool, Rejection> {
+    match data.get(User::PWD).cloned() {
+        Some(Bson::String(s)) => {
+            if s.parse::<UserPassword>().is_err() {
+                return bad_request("Invalid password");
+            }
+            let hash = hash_password(s).await?;
+            data.insert(User::PWD, hash);
+            Ok(true)
+        }
+        Some(_) => bad_request("Invalid password"),
+        None => Ok(false),
+    }
 }
 
 /// User kinds.
@@ -47,6 +142,7 @@ pub struct User {
     pub data: Document,
 
     id: Option<String>,
+    original: Option<Document>,
 
     /// User that owns this user object.
     user: UserKind,
@@ -71,49 +167,56 @@ impl User {
         pwd: &str,
     ) -> Result<(Document, ClientToken), Rejection> {
         let doc = doc! { Self::NAME : name, Self::PWD : pwd};
-        trace!("signup: name {}, pwd {}, doc {}", name, pwd, doc);
+        trace!("signup: name {}", name);
         self.data = doc;
         let d = self.save().await?;
 
         let id = d.get_str(database::OBJECT_ID).unwrap().to_string();
-        let token = ClientToken {
-            sub: id.clone(),
-            exp: chrono::Utc::now().timestamp() + 900, // Expire after 15min.
-            id,
-            name: name.to_owned(),
-        };
+        let token = ClientToken::new(&self.ctx.config, id, name.to_owned());
         Ok((d.to_owned(), token))
     }
 
     /// Log in with username and password, updating this user instance.
+    ///
+    /// Passwords stored in plaintext by previous versions are hashed once matched.
     pub async fn login(
         &mut self,
         name: &str,
         pwd: &str,
     ) -> Result<(Document, ClientToken), Rejection> {
-        let filter = doc! {Self::NAME: name, Self::PWD : pwd};
+        let filter = doc! {Self::NAME: name};
         trace!("login filter: {:?}", filter);
         let v = self
             .ctx
             .db
-            .retrieve("_User", filter, UserKind::Master)
+            .retrieve("_User", filter.into(), UserKind::Master)
             .await?;
 
         if let Some(d) = v.first() {
             if v.len() != 1 {
                 return internal_server_error("User ID not unique");
             }
+            let stored = d.get_str(Self::PWD).unwrap_or_default().to_string();
+            if stored.is_empty() || !verify_password(pwd.to_string(), stored.clone()).await? {
+                return unauthorized("");
+            }
             let id = d.get_str(database::OBJECT_ID).unwrap().to_string();
-            let token = ClientToken {
-                sub: id.clone(),
-                exp: chrono::Utc::now().timestamp() + 900, // Expire after 15min.
-                id,
-                name: name.to_owned(),
-            };
+            if legacy_password(&stored) {
+                trace!("rehash legacy password of user {}", id);
+                let hash = hash_password(pwd.to_string()).await?;
+                self.ctx
+                    .db
+                    .update("_User", &id, doc! {Self::PWD: hash}, UserKind::Master)
+                    .await?;
+            }
+            let mut d = d.clone();
+            d.remove(Self::PWD);
+            let token = ClientToken::new(&self.ctx.config, id, name.to_owned());
             self.data = d.clone();
             // self.kind = UserKind::Client(token.clone());
             Ok((d.to_owned(), token))
         } else {
+            verify_password(pwd.to_string(), DUMMY_HASH.clone()).await?;
             unauthorized("")
         }
     }
@@ -125,6 +228,7 @@ impl ObjectTrait for User {
         User {
             data: Document::default(),
             id: None,
+            original: None,
             user,
             ctx,
         }
@@ -143,25 +247,19 @@ impl ObjectTrait for User {
     async fn save(&mut self) -> Result<Document, Rejection> {
         // Validate name and password.
         let mut name = None;
-        let mut pwd = None;
         if let Ok(s) = self.data.get_str(Self::NAME) {
             if let Err(s) = s.to_string().parse::<UserName>() {
                 return bad_request("Invalid username");
             } else {
-                name = Some(s);
-            }
-        };
-        if let Ok(s) = self.data.get_str(Self::PWD) {
-            if let Err(s) = s.to_string().parse::<UserPassword>() {
-                return bad_request("Invalid password");
-            } else {
-                pwd = Some(s)
+                name = Some(s.to_string());
             }
         };
+        // Passwords are stored as hashes only.
+        let pwd = hash_password_of(&mut self.data).await?;
 
         if let Some(ref id) = self.id {
             // Update
-            let result = match self.user.clone() {
+            let (old, new) = match self.user.clone() {
                 UserKind::Client(t) => {
                     if &t.id == id {
                         // Client can update itself.
@@ -185,15 +283,25 @@ impl ObjectTrait for User {
                         .await?
                 }
             };
-            Ok(result)
+            if pwd {
+                session::revoke_others(&self.ctx, id, &self.user).await?;
+            }
+            // Hash of password is never returned.
+            let (mut old, mut new) = (old, new);
+            old.remove(Self::PWD);
+            new.remove(Self::PWD);
+            self.original = Some(old);
+            Ok(new)
         } else {
             // Create
-            if let (Some(name), Some(pwd)) = (name, pwd) {
+            if let (Some(name), true) = (name, pwd) {
                 let doc = (*self.ctx)
                     .db
                     .create("_User", self.data.clone(), self.user.clone())
                     .await
                     .map_or_else(|d| conflict("User exists"), |d| Ok(d))?;
+                let mut doc = doc;
+                doc.remove(Self::PWD);
                 self.data = doc.clone();
                 Ok(doc)
             } else {
@@ -202,12 +310,16 @@ impl ObjectTrait for User {
         }
     }
 
+    fn original(&self) -> Option<&Document> {
+        self.original.as_ref()
+    }
+
     async fn destroy(&mut self) -> Result<Document, Rejection> {
         unimplemented!("Do not support destroying user")
     }
 }
 
-/// Encode a token struct `t` by key.
+/// Encode a token struct `t` by HS256 with secret `key`, as servers without signing keys do.
 pub fn encode_token(t: &ClientToken, key: &str) -> Result<String, Rejection> {
     jsonwebtoken::encode(
         &Header::default(),
@@ -220,11 +332,10 @@ pub fn encode_token(t: &ClientToken, key: &str) -> Result<String, Rejection> {
     )
 }
 
-/// Decode token string `s` by key.
+/// Decode token string `s` signed by HS256 with secret `key`.
 ///
 /// Return error if token invalid or expire.
 pub fn decode_token(s: &str, key: &str) -> Result<ClientToken, Rejection> {
-    trace!("decode token: {} by key {}", s, key);
     match jsonwebtoken::decode::<ClientToken>(
         &s,
         &DecodingKey::from_secret(key.as_ref()),
@@ -234,14 +345,14 @@ pub fn decode_token(s: &str, key: &str) -> Result<ClientToken, Rejection> {
         Err(e) => {
             error!(
                 "user token expired or wrong: {}, server may be under attack",
-                s
+                e
             );
             bad_request("Token invalid, maybe expired")
         }
     }
 }
 
-/// Login query struct.
+/// Login query struct, from query string or body.
 #[derive(Deserialize, Serialize)]
 pub struct LoginQuery {
     username: String,
@@ -260,23 +371,21 @@ pub async fn signup(req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejec
         let id = d.get_str(database::OBJECT_ID).unwrap().to_string();
         let name = d.get_str(User::NAME).unwrap();
 
-        let token = ClientToken {
-            sub: id.clone(),
-            exp: chrono::Utc::now().timestamp() + 900, // Expire after 15min.
-            id,
-            name: name.to_owned(),
-        };
+        let mut token = ClientToken::new(&ctx.config, id, name.to_owned());
+        let refresh = session::start(&ctx, &mut token, &req.headers, "signup").await?;
 
-        encode_token(&token, &ctx.config.secret).map_or_else(
+        ctx.keys.encode(&token).map_or_else(
             |e| internal_server_error("Failed to encode JWT token"),
             |t| {
                 d.insert("sessionToken", t);
                 Ok(())
             },
         )?;
+        d.insert("refreshToken", refresh);
 
-        let result = serde_json::to_string(&d)
-            .map_or_else(|e| internal_server_error("Serialization error"), |s| Ok(s))?;
+        // The user signed up can see its own fields.
+        let d = schema::protect(&ctx, "_User", &UserKind::Client(token), d);
+        let result = types::to_json(d)?;
         Ok(warp::reply::with_status(
             result,
             warp::http::StatusCode::CREATED,
@@ -286,7 +395,7 @@ pub async fn signup(req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejec
     }
 }
 
-/// Login with query of username and password, used by RESTFul API.
+/// Login with username and password in query string or body, used by RESTFul API.
 pub async fn login(
     q: LoginQuery,
     req: Request,
@@ -294,32 +403,94 @@ pub async fn login(
 ) -> Result<impl Reply, Rejection> {
     trace!("user login");
 
-    let secret = ctx.config.secret.clone();
-    let mut user = User::from_context(ctx, req.user.clone());
-    let result = user.login(&q.username, &q.password).await.map_or_else(
-        |_e| unauthorized("User not found or password error"),
-        |(mut d, t)| {
-            d.insert("sessionToken", encode_token(&t, &secret)?);
-            serde_json::to_string(&d)
-                .map_or_else(|e| internal_server_error("Serialization error"), |s| Ok(s))
-        },
-    );
-    result
+    let mut user = User::from_context(ctx.clone(), req.user.clone());
+    let (mut d, mut t) = match user.login(&q.username, &q.password).await {
+        Ok(r) => r,
+        Err(_) => return unauthorized("User not found or password error"),
+    };
+    let refresh = session::start(&ctx, &mut t, &req.headers, "login").await?;
+    d.insert("sessionToken", ctx.keys.encode(&t)?);
+    d.insert("refreshToken", refresh);
+    // The user logged in can see its own fields.
+    types::to_json(schema::protect(&ctx, "_User", &UserKind::Client(t), d))
+}
+
+/// Retrieve the requesting user with its session token, used by RESTFul API.
+pub async fn me(req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejection> {
+    let id = match &req.user {
+        UserKind::Client(t) => t.id.clone(),
+        _ => return unauthorized("Not logged in"),
+    };
+    let filter = doc! {database::OBJECT_ID: id};
+    let v = ctx
+        .db
+        .retrieve("_User", filter.into(), UserKind::Master)
+        .await?;
+    match v.into_iter().next() {
+        Some(mut d) => {
+            let token = req.headers.get("x-parse-session-token");
+            if let Some(t) = token.and_then(|t| t.to_str().ok()) {
+                d.insert("sessionToken", t);
+            }
+            types::to_json(schema::protect(&ctx, "_User", &req.user, d))
+        }
+        None => not_found("User not found"),
+    }
+}
+
+/// Retrieve user by id, used by RESTFul API.
+///
+/// Users not readable by the requesting user due to ACL are not found.
+pub async fn retrieve(
+    id: String,
+    req: Request,
+    ctx: Arc<Context>,
+) -> Result<impl Reply, Rejection> {
+    schema::authorize(&ctx, "_User", ClassOperation::Get, &req.user).await?;
+    let filter = doc! {database::OBJECT_ID: id};
+    let v = ctx
+        .db
+        .retrieve("_User", filter.into(), req.user.clone())
+        .await?;
+    match v.into_iter().next() {
+        Some(d) => types::to_json(schema::protect(&ctx, "_User", &req.user, d)),
+        None => not_found("User not found"),
+    }
 }
 
 /// Update user by id, used by RESTFul API.
 ///
-/// Master can update any user and Client can only update itself.
-pub async fn update(id: String, req: Request, ctx: Arc<Context>) -> Result<impl Reply, Rejection> {
+/// Master can update any user and Client can only update itself. The response is the user updated,
+/// or `{"original": ..., "updated": ...}` with both before and after updating if `original=1`
+/// is in query string. ACL of the user can be replaced by `ACL` field only, validated the same
+/// as that of other objects, see `object::update`.
+pub async fn update(
+    id: String,
+    q: HashMap<String, String>,
+    req: Request,
+    ctx: Arc<Context>,
+) -> Result<impl Reply, Rejection> {
     trace!("user update {}", &id);
-    if let Some(body) = req.body {
-        let mut user = User::from_context(ctx, req.user.clone());
+    let original = query::flag(&q, "original")?;
+    if let Some(mut body) = req.body {
+        let acl = object::take_acl(&ctx, "_User", Some(&id), &mut body, &req.user).await?;
+        if let Some(acl) = acl {
+            body.insert(database::ACL, Document::from(acl));
+        }
+        let mut user = User::from_context(ctx.clone(), req.user.clone());
         user.set_id(id);
         user.set_data(body);
 
         let d = user.save().await?;
-        serde_json::to_string(&d)
-            .map_or_else(|e| internal_server_error("Serialization error"), |s| Ok(s))
+        let requester = &req.user;
+        let protect = |d: Document| schema::protect(&ctx, "_User", requester, d);
+        if original {
+            let old = user.original().cloned();
+            let old = old.map_or(Bson::Null, |d| Bson::Document(protect(d)));
+            types::to_json(doc! {"original": old, "updated": protect(d)})
+        } else {
+            types::to_json(protect(d))
+        }
     } else {
         bad_request("Cannot update user with empty body")
     }
@@ -327,14 +498,23 @@ pub async fn update(id: String, req: Request, ctx: Arc<Context>) -> Result<impl
 
 #[cfg(test)]
 pub(crate) mod tests {
-    use std::sync::Arc;
+    use std::{collections::HashMap, sync::Arc};
 
+    use mongodb::bson::doc;
     use serde_json::{json, Value};
-    use warp::hyper::StatusCode;
+    use warp::{hyper::StatusCode, Rejection};
 
-    use crate::{tests::TEST_SERVER_KEY, with_user};
+    use crate::{
+        object::{Object, ObjectTrait},
+        server::{Context, Request},
+        tests::TEST_SERVER_KEY,
+        with_user,
+    };
 
-    use super::{super::tests::test_api, decode_token, encode_token, ClientToken};
+    use super::{
+        super::tests::{test_api, test_server},
+        decode_token, encode_token, ClientToken, UserKind,
+    };
 
     /// Create a temp user token by user ID `uid` with expiration time of 10000s.
     pub fn tmp_token(uid: impl Into<String>) -> String {
@@ -345,6 +525,8 @@ pub(crate) mod tests {
             id: id.clone(),
             name: "whatever".to_string(),
             exp: now + 10000,
+            roles: vec![],
+            jti: String::new(),
         };
         encode_token(&t, crate::tests::TEST_SERVER_KEY).expect("error when encoding")
     }
@@ -386,6 +568,8 @@ pub(crate) mod tests {
             id: "x".to_string(),
             name: "foo".to_string(),
             exp: now + 100,
+            roles: vec![],
+            jti: String::new(),
         };
         let s = encode_token(&t, TEST_SERVER_KEY).expect("error when encoding");
         let dt = decode_token(&s, TEST_SERVER_KEY).expect("error when decoding");
@@ -402,6 +586,8 @@ pub(crate) mod tests {
             id: "x".to_string(),
             name: "foo".to_string(),
             exp: now - 1,
+            roles: vec![],
+            jti: String::new(),
         };
         let s = encode_token(&t, TEST_SERVER_KEY).expect("error when encoding");
         assert!(decode_token(&s, TEST_SERVER_KEY).is_err());
@@ -433,7 +619,7 @@ pub(crate) mod tests {
         let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
         assert_eq!(resp.status(), StatusCode::CREATED);
         assert_eq!(body.get("username").unwrap(), "foobar");
-        assert_eq!(body.get("password").unwrap(), "12345");
+        assert!(body.get("password").is_none());
         assert!(body.get("createdAt").is_some());
         assert!(body.get("updatedAt").is_some());
         assert!(body.get("sessionToken").is_some());
@@ -448,7 +634,7 @@ pub(crate) mod tests {
         let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
         assert_eq!(body.get("username").unwrap(), "foobar");
-        assert_eq!(body.get("password").unwrap(), "12345");
+        assert!(body.get("password").is_none());
         assert!(body.get("createdAt").is_some());
         assert!(body.get("updatedAt").is_some());
         assert!(body.get("sessionToken").is_some());
@@ -473,6 +659,86 @@ pub(crate) mod tests {
         debug!("resp {:?}", resp);
     }
 
+    /// Store a legacy user with plaintext password, another one whose password starts with `$`
+    /// with `dollar`, or another one by `Object` with `object`, returning passwords stored of
+    /// all users.
+    async fn legacy_user(
+        _req: Request,
+        ctx: Arc<Context>,
+        arg: HashMap<String, String>,
+    ) -> Result<String, Rejection> {
+        if arg.contains_key("create") {
+            let d = doc! {"username": "legacy", "password": "12345"};
+            ctx.db.create("_User", d, UserKind::Master).await?;
+        }
+        if arg.contains_key("dollar") {
+            let d = doc! {"username": "dollar", "password": "$12345"};
+            ctx.db.create("_User", d, UserKind::Master).await?;
+        }
+        if arg.contains_key("object") {
+            let mut obj = Object::from_context(ctx.clone(), UserKind::Master);
+            obj.set_class("_User");
+            obj.set_data(doc! {"username": "object", "password": "12345"});
+            obj.save().await?;
+        }
+        let v = ctx
+            .db
+            .retrieve("_User", Default::default(), UserKind::Master)
+            .await?;
+        let v: Vec<&str> = v.iter().map(|d| d.get_str("password").unwrap()).collect();
+        Ok(v.join(","))
+    }
+
+    #[tokio::test]
+    async fn test_password_hash() {
+        let mut s = test_server().await;
+        s.define(
+            "legacy",
+            Box::new(|req, ctx, arg| Box::pin(legacy_user(req, ctx, arg))),
+        );
+        let api = s.routes().await;
+        let passwords1 = async move |api, query| {
+            let resp = warp::test::request()
+                .path(&format!("/functions/legacy?{}", query))
+                .reply(api)
+                .await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            String::from_utf8_lossy(&resp.body()[..]).to_string()
+        };
+
+        assert_eq!(passwords1(&api, "create=1").await, "12345");
+        let resp = login1!(&api, "legacy", "123456");
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(passwords1(&api, "").await, "12345");
+
+        // Rehashed on login.
+        let resp = login1!(&api, "legacy", "12345");
+        assert_eq!(resp.status(), StatusCode::OK);
+        let hash = passwords1(&api, "").await;
+        assert!(hash.starts_with("$2"));
+        let resp = login1!(&api, "legacy", "12345");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(passwords1(&api, "").await, hash);
+        let resp = login1!(&api, "legacy", hash);
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = signup1!(&api, "foobar", "12345");
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let v = passwords1(&api, "object=1").await;
+        assert!(v.split(',').all(|p| p.starts_with("$2") && p != hash));
+        let resp = login1!(&api, "object", "12345");
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Unknown users are rejected the same.
+        let resp = login1!(&api, "unknown", "12345");
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Legacy passwords taken as invalid hashes never match.
+        passwords1(&api, "dollar=1").await;
+        let resp = login1!(&api, "dollar", "$12345");
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
     #[tokio::test]
     async fn test_update() {
         let api = test_api().await;
@@ -527,6 +793,15 @@ pub(crate) mod tests {
         let resp = update1_with_client(&api, uid, uid, json!({"password": "a"})).await;
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
 
+        // Client cannot set ACL but by validated `ACL`.
+        let acl = json!({ uid: {"read": true, "write": true, "delete": true} });
+        let resp = update1_with_client(&api, uid, uid, json!({ "acl": acl })).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = update1_with_client(&api, uid, uid, json!({"acl.*": "w"})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = update1_with_client(&api, uid, uid, json!({"ACL": {"*": "x"}})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
         // Client can update itself.
         let resp = update1_with_client(
             &api,
@@ -537,10 +812,10 @@ pub(crate) mod tests {
         .await;
         let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
         dbg!(&body);
-        // Should return value before update.
-        assert_eq!(body.get("username").unwrap().as_str().unwrap(), "foobar");
+        // Should return value after update.
+        assert_eq!(body.get("username").unwrap().as_str().unwrap(), "abcdefg");
         assert_eq!(body.get("objectId").unwrap().as_str().unwrap(), uid);
-        assert_eq!(body.get("arbitrary"), None);
+        assert_eq!(body.get("arbitrary").unwrap().as_str().unwrap(), "data");
         assert_eq!(resp.status(), StatusCode::OK);
 
         // Master can update any user.
@@ -553,10 +828,105 @@ pub(crate) mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
         let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
         dbg!(&body);
-        assert_eq!(body.get("username").unwrap().as_str().unwrap(), "abcdefg");
-        assert_eq!(body.get("password").unwrap().as_str().unwrap(), "123456");
+        assert_eq!(body.get("username").unwrap().as_str().unwrap(), "123456");
+        assert!(body.get("password").is_none());
         assert_eq!(body.get("arbitrary").unwrap().as_str().unwrap(), "data");
         assert_eq!(body.get("objectId").unwrap().as_str().unwrap(), uid);
         assert_eq!(resp.status(), StatusCode::OK);
+
+        // Password is updated though never returned.
+        let resp = login1!(&api, "123456", "0123456");
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_login_by_body() {
+        let api = test_api().await;
+        let resp = signup1!(&api, "foobar", "12345");
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/login")
+            .json(&json!({"username": "foobar", "password": "12345"}))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
+        assert_eq!(body["username"], json!("foobar"));
+        assert!(body.get("sessionToken").is_some());
+
+        let form1 = async move |api, body: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/login")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(body.to_string())
+                .reply(api)
+                .await
+                .status()
+        };
+        assert_eq!(
+            form1(&api, "username=foobar&password=12345").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            form1(&api, "username=foobar&password=123456").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_user() {
+        let api = test_api().await;
+        let get1 = async move |api, token: &str, path: &str| {
+            let mut req = warp::test::request().path(path);
+            if token == TEST_SERVER_KEY {
+                req = req.header("x-parse-master-key", token);
+            } else if !token.is_empty() {
+                req = req.header("x-parse-session-token", token);
+            }
+            let resp = req.reply(api).await;
+            let body = serde_json::from_slice::<Value>(&resp.body()[..]).unwrap();
+            (resp.status(), body)
+        };
+
+        let resp = signup1!(&api, "foobar", "12345");
+        let body: Value = serde_json::from_slice(&resp.body()[..]).unwrap();
+        let token = body["sessionToken"].as_str().unwrap().to_string();
+        let uid = body["objectId"].as_str().unwrap().to_string();
+        let path = format!("/users/{}", uid);
+
+        let (status, body) = get1(&api, &token, "/users/me").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["objectId"], json!(uid));
+        assert_eq!(body["sessionToken"], json!(token));
+        assert!(body.get("password").is_none());
+        let (status, _) = get1(&api, "", "/users/me").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = get1(&api, "", &path).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["username"], json!("foobar"));
+        assert!(body.get("password").is_none());
+
+        // Only readable by the user itself.
+        let none = json!({"read": false, "write": false, "delete": false});
+        let acl = json!({ uid.as_str(): {"read": true, "write": true, "delete": true}, "*": none });
+        let resp = warp::test::request()
+            .method("POST")
+            .path(&path)
+            .header("x-parse-master-key", TEST_SERVER_KEY)
+            .json(&json!({ "acl": acl }))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let (status, _) = get1(&api, "", &path).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get1(&api, &tmp_token("a"), &path).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = get1(&api, &token, &path).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = get1(&api, TEST_SERVER_KEY, &path).await;
+        assert_eq!(status, StatusCode::OK);
     }
 }